@@ -0,0 +1,474 @@
+//! `absorb`: turn staged changes into `fixup!` commits in the branches that introduced the lines
+//! they change (like git-absorb, but across a whole stack of local branches), and restack.
+
+use git2::build::TreeUpdateBuilder;
+use git2::{Commit, Delta, Diff, DiffOptions, FileMode, Oid, Patch, Repository, Tree};
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use crate::git::{self, HeadGuard, RebaseOptions};
+use crate::util::loop_until_confirm;
+
+/// A staged hunk, identified by its file and header.
+#[derive(Debug, Clone, PartialEq)]
+struct Hunk {
+    path: PathBuf,
+    old_start: u32,
+    old_lines: u32,
+    new_start: u32,
+    new_lines: u32,
+}
+
+/// The contents of the file at `path` in `tree`.
+fn contents(repo: &Repository, tree: &Tree, path: &Path) -> Result<Vec<u8>, git2::Error> {
+    let blob = tree.get_path(path)?.to_object(repo)?.peel_to_blob()?;
+    Ok(blob.content().to_vec())
+}
+
+/// The lines of `text`, each with its line ending.
+fn lines(text: &[u8]) -> Vec<&[u8]> {
+    text.split_inclusive(|byte| *byte == b'\n').collect()
+}
+
+/// Where the `count` lines starting at line `first` of `new` are in `old`, if they're there
+/// unchanged and still next to each other. Commits higher up in the stack add and remove lines,
+/// so a staged hunk's line numbers (which are relative to `HEAD`) usually don't hold in a lower
+/// branch.
+fn locate(old: &[u8], new: &[u8], first: u32, count: u32) -> Result<Option<u32>, git2::Error> {
+    let mut options = DiffOptions::new();
+    options.context_lines(0).interhunk_lines(0);
+    let patch = Patch::from_buffers(old, None, new, None, Some(&mut options))?;
+
+    // Each hunk is a range of lines in `new` that aren't in `old` (a pure deletion sits just
+    // after `new_start`), and shifts everything after it by the difference in length
+    let mut changes = vec![];
+    for i in 0..patch.num_hunks() {
+        let (hunk, _) = patch.hunk(i)?;
+        let end = hunk.new_start() + hunk.new_lines().max(1);
+        let start = if hunk.new_lines() == 0 {
+            end
+        } else {
+            hunk.new_start()
+        };
+        changes.push((
+            start,
+            end,
+            hunk.old_lines() as i64 - hunk.new_lines() as i64,
+        ));
+    }
+
+    let map = |line: u32| -> Option<u32> {
+        let mut shift = 0;
+        for (start, end, delta) in &changes {
+            if line >= *end {
+                shift += delta;
+            } else if line >= *start {
+                return None;
+            }
+        }
+        Some((line as i64 + shift) as u32)
+    };
+
+    let start = match map(first) {
+        Some(start) => start,
+        None => return Ok(None),
+    };
+    let contiguous = (0..count).all(|offset| map(first + offset) == Some(start + offset));
+
+    Ok(if contiguous { Some(start) } else { None })
+}
+
+/// The staged changes, as a diff between `HEAD` and the index. Hunks have no context, so each
+/// one only covers lines that actually changed.
+fn staged_diff<'a>(
+    repo: &'a Repository,
+    head: &Commit,
+    index_tree: Oid,
+) -> Result<Diff<'a>, git2::Error> {
+    let mut options = DiffOptions::new();
+    options.context_lines(0).interhunk_lines(0);
+
+    let index_tree = repo.find_tree(index_tree)?;
+    repo.diff_tree_to_tree(Some(&head.tree()?), Some(&index_tree), Some(&mut options))
+}
+
+fn staged_hunks(diff: &Diff) -> Result<Vec<Hunk>, git2::Error> {
+    let mut hunks = vec![];
+
+    diff.foreach(
+        &mut |_, _| true,
+        None,
+        Some(&mut |delta, hunk| {
+            if delta.status() == Delta::Modified {
+                hunks.push(Hunk {
+                    path: delta.old_file().path().unwrap().to_path_buf(),
+                    old_start: hunk.old_start(),
+                    old_lines: hunk.old_lines(),
+                    new_start: hunk.new_start(),
+                    new_lines: hunk.new_lines(),
+                });
+            }
+            true
+        }),
+        None,
+    )?;
+
+    Ok(hunks)
+}
+
+/// The commit in the stack (above `trunk`) that last touched every line `hunk` changes, if
+/// there's exactly one. Hunks that only add lines don't change anything that can be blamed.
+fn owner(
+    repo: &Repository,
+    head: Oid,
+    trunk: Oid,
+    hunk: &Hunk,
+) -> Result<Option<Oid>, git2::Error> {
+    if hunk.old_lines == 0 {
+        return Ok(None);
+    }
+
+    let first = hunk.old_start as usize;
+    let mut options = git2::BlameOptions::new();
+    options
+        .newest_commit(head)
+        .oldest_commit(trunk)
+        .min_line(first)
+        .max_line(first + hunk.old_lines as usize - 1);
+
+    let blame = repo.blame_file(&hunk.path, Some(&mut options))?;
+    let mut commits = blame.iter().map(|line| line.final_commit_id());
+
+    let commit = match commits.next() {
+        Some(commit) => commit,
+        None => return Ok(None),
+    };
+    let in_stack = !git::is_ancestor(repo, commit, trunk);
+
+    if in_stack && commits.all(|other| other == commit) {
+        Ok(Some(commit))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Commit the staged `hunks` on top of `tip`, as a fixup for `target`. Each hunk's lines are
+/// found in `tip`'s version of the file and replaced with their staged version (from
+/// `index_tree`).
+fn fixup<'a>(
+    repo: &'a Repository,
+    tip: &Commit<'a>,
+    target: Oid,
+    head: &Tree,
+    index_tree: &Tree,
+    hunks: &[Hunk],
+) -> Result<Commit<'a>, Box<dyn Error>> {
+    let target = repo.find_commit(target)?;
+    let tip_tree = tip.tree()?;
+    let mut paths = hunks.iter().map(|hunk| &hunk.path).collect::<Vec<_>>();
+    paths.dedup();
+
+    let mut update = TreeUpdateBuilder::new();
+    for path in paths {
+        let base = contents(repo, &tip_tree, path)?;
+        let before = contents(repo, head, path)?;
+        let after = contents(repo, index_tree, path)?;
+        let (mut result, staged) = (lines(&base), lines(&after));
+
+        let mut located = vec![];
+        for hunk in hunks.iter().filter(|hunk| &hunk.path == path) {
+            let start =
+                locate(&base, &before, hunk.old_start, hunk.old_lines)?.ok_or_else(|| {
+                    format!(
+                        "Couldn't find {}:{} in {:?}",
+                        path.display(),
+                        hunk.old_start,
+                        tip
+                    )
+                })?;
+            located.push((start, hunk));
+        }
+
+        // Bottom to top, so replacing one hunk doesn't move the others
+        located.sort_by_key(|(start, _)| std::cmp::Reverse(*start));
+        for (start, hunk) in located {
+            // A hunk that only deletes lines starts after them
+            let from = hunk.new_start.saturating_sub(1) as usize;
+            let replacement = match hunk.new_lines {
+                0 => &[][..],
+                count => &staged[from..from + count as usize],
+            };
+            let start = (start - 1) as usize;
+            result.splice(
+                start..start + hunk.old_lines as usize,
+                replacement.iter().copied(),
+            );
+        }
+
+        let blob = repo.blob(&result.concat())?;
+        let mode = match tip_tree.get_path(path)?.filemode() {
+            0o100755 => FileMode::BlobExecutable,
+            _ => FileMode::Blob,
+        };
+        update.upsert(path, blob, mode);
+    }
+
+    let tree = repo.find_tree(update.create_updated(repo, &tip_tree)?)?;
+
+    let message = format!("fixup! {}", target.summary().unwrap_or_default());
+    let signature = repo.signature()?;
+    let oid = repo.commit(None, &signature, &signature, &message, &tree, &[tip])?;
+
+    println!("Created {}: {}", oid, message);
+    Ok(repo.find_commit(oid)?)
+}
+
+/// Everything `absorb` works out before touching any refs.
+struct Plan {
+    head: Oid,
+    index_tree: Oid,
+    parents: HashMap<String, String>,
+    /// Every branch that might have to be rebuilt, parents first (never the trunk)
+    order: Vec<String>,
+    tips: HashMap<String, Oid>,
+    /// The fixups (target commit and hunks) to add to each branch
+    fixups: HashMap<String, Vec<(Oid, Vec<Hunk>)>>,
+}
+
+fn plan(repo: &Repository, base: Option<&str>) -> Result<Option<Plan>, Box<dyn Error>> {
+    let head = repo.head()?;
+    if !head.is_branch() {
+        return Err("HEAD is detached; check out a branch in the stack first.".into());
+    }
+    let branch = head.shorthand().unwrap().to_string();
+    let head = head.peel_to_commit()?;
+
+    // The branches between the trunk and the current branch, bottom first. Every chain ends at
+    // the trunk, which is never part of the stack itself.
+    let (trunk, trunk_tip) = git::trunk(repo, base)?;
+    let parents = git::local_parents(repo, base)?;
+    if !parents.contains_key(&branch) {
+        return Err(format!("{} isn't stacked on {}", branch, trunk).into());
+    }
+    let mut chain = vec![branch.clone()];
+    while let Some(parent) = parents.get(chain.last().unwrap()) {
+        if *parent == trunk {
+            break;
+        }
+        chain.push(parent.clone());
+    }
+    chain.reverse();
+
+    let mut order = chain.clone();
+    order.extend(git::local_descendants(&parents, &branch));
+
+    let mut tips = HashMap::new();
+    for branch in &order {
+        tips.insert(
+            branch.clone(),
+            repo.refname_to_id(&format!("refs/heads/{}", branch))?,
+        );
+    }
+
+    let index_tree = repo.index()?.write_tree()?;
+    let diff = staged_diff(repo, &head, index_tree)?;
+
+    let mut targets: Vec<(Oid, Vec<Hunk>)> = vec![];
+    let mut skipped = 0;
+    for hunk in staged_hunks(&diff)? {
+        match owner(repo, head.id(), trunk_tip, &hunk)? {
+            Some(commit) => match targets.iter_mut().find(|(target, _)| *target == commit) {
+                Some((_, hunks)) => hunks.push(hunk),
+                None => targets.push((commit, vec![hunk])),
+            },
+            None => skipped += 1,
+        }
+    }
+
+    // A commit belongs to the lowest branch that contains it
+    let head_tree = head.tree()?;
+    let mut fixups: HashMap<String, Vec<(Oid, Vec<Hunk>)>> = HashMap::new();
+    for (target, hunks) in targets {
+        let branch = chain
+            .iter()
+            .find(|branch| git::is_ancestor(repo, target, tips[*branch]))
+            .unwrap();
+
+        // Lines that were moved apart higher up in the stack can't be fixed up in one piece
+        let branch_tree = repo.find_commit(tips[branch])?.tree()?;
+        let mut located = vec![];
+        for hunk in hunks {
+            let base = contents(repo, &branch_tree, &hunk.path)?;
+            let before = contents(repo, &head_tree, &hunk.path)?;
+            match locate(&base, &before, hunk.old_start, hunk.old_lines)? {
+                Some(_) => located.push(hunk),
+                None => skipped += 1,
+            }
+        }
+        if located.is_empty() {
+            continue;
+        }
+        let hunks = located;
+
+        println!(
+            "{} (on {}):",
+            repo.find_commit(target)?.summary().unwrap_or_default(),
+            branch
+        );
+        for hunk in &hunks {
+            println!("  {}:{}", hunk.path.display(), hunk.old_start);
+        }
+
+        fixups
+            .entry(branch.clone())
+            .or_default()
+            .push((target, hunks));
+    }
+
+    if fixups.is_empty() {
+        println!("None of the staged changes can be traced back to a single commit in the stack.");
+        return Ok(None);
+    }
+
+    if skipped > 0 {
+        println!(
+            "\n{} staged hunks can't be absorbed, and will stay staged.",
+            skipped
+        );
+    }
+
+    Ok(Some(Plan {
+        head: head.id(),
+        index_tree,
+        parents,
+        order,
+        tips,
+        fixups,
+    }))
+}
+
+/// Add the fixups to their branches, and rebuild every branch above them.
+fn rebuild(repo: &Repository, plan: &Plan) -> Result<(), Box<dyn Error>> {
+    let _head = HeadGuard::new(repo);
+
+    let options = RebaseOptions::default();
+
+    let head = repo.find_commit(plan.head)?.tree()?;
+    let index_tree = repo.find_tree(plan.index_tree)?;
+    let mut new_tips: HashMap<&str, Commit> = HashMap::new();
+
+    for branch in &plan.order {
+        let parent = &plan.parents[branch];
+
+        // The bottom branch's parent is the trunk, which stays where it is
+        let mut tip = match new_tips.get(&parent[..]) {
+            Some(onto) if onto.id() != plan.tips[parent] => {
+                let branches = [(&branch[..], plan.tips[parent])];
+                git::replay_branches(repo, onto.clone(), &branches, options)
+                    .pop()
+                    .unwrap()
+                    .1
+            }
+            _ => repo.find_commit(plan.tips[branch])?,
+        };
+
+        for (target, hunks) in plan.fixups.get(branch).into_iter().flatten() {
+            tip = fixup(repo, &tip, *target, &head, &index_tree, hunks)?;
+        }
+
+        new_tips.insert(branch, tip);
+    }
+
+    println!("\nUpdating local branches so they point to the new stack.\n");
+    for branch in &plan.order {
+        let target = &new_tips[&branch[..]];
+        if target.id() != plan.tips[branch] {
+            println!("  + Branch {} now points to {}", branch, target.id());
+            git::update_branch(repo, branch, target)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Absorb the staged changes into the branches of the current stack (worked out from local
+/// branches, down to `base` or the default trunk; see `git::trunk`). Staged hunks that can't be
+/// traced back to a single commit in the stack stay staged.
+pub fn absorb(repo: &mut Repository, base: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let plan = match plan(repo, base)? {
+        Some(plan) => plan,
+        None => return Ok(()),
+    };
+
+    loop_until_confirm("Going to create fixup commits for these changes ☝️ ");
+
+    // Restacking might need the working tree to resolve conflicts
+    let stashed = git::stash(repo)?;
+
+    let result = rebuild(repo, &plan);
+
+    if stashed {
+        git::unstash(repo)?;
+    }
+
+    // Put the index back the way it was; now that the absorbed changes are part of `HEAD`, only
+    // the rest show up as staged
+    let mut index = repo.index()?;
+    index.read_tree(&repo.find_tree(plan.index_tree)?)?;
+    index.write()?;
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locate_finds_unchanged_lines() {
+        let text = b"a\nb\nc\n";
+        assert_eq!(locate(text, text, 2, 2).unwrap(), Some(2));
+    }
+
+    #[test]
+    fn locate_follows_lines_added_above() {
+        let old = b"a\nb\nc\n";
+        let new = b"x\ny\na\nb\nc\n";
+        assert_eq!(locate(old, new, 4, 1).unwrap(), Some(2));
+        assert_eq!(locate(old, new, 3, 3).unwrap(), Some(1));
+    }
+
+    #[test]
+    fn locate_follows_lines_removed_above() {
+        let old = b"a\nb\nc\nd\n";
+        let new = b"a\nd\n";
+        assert_eq!(locate(old, new, 1, 1).unwrap(), Some(1));
+        assert_eq!(locate(old, new, 2, 1).unwrap(), Some(4));
+    }
+
+    #[test]
+    fn locate_ignores_lines_that_were_changed_later() {
+        let old = b"a\nb\nc\n";
+        let new = b"a\nB\nc\n";
+        assert_eq!(locate(old, new, 2, 1).unwrap(), None);
+        assert_eq!(locate(old, new, 1, 2).unwrap(), None);
+        assert_eq!(locate(old, new, 3, 1).unwrap(), Some(3));
+    }
+
+    #[test]
+    fn locate_ignores_lines_that_were_moved_together_later() {
+        let old = b"a\nb\nx\nc\n";
+        let new = b"a\nb\nc\n";
+        assert_eq!(locate(old, new, 2, 2).unwrap(), None);
+        assert_eq!(locate(old, new, 3, 1).unwrap(), Some(4));
+    }
+
+    #[test]
+    fn locate_ignores_lines_added_later() {
+        let old = b"a\nc\n";
+        let new = b"a\nb\nc\n";
+        assert_eq!(locate(old, new, 2, 1).unwrap(), None);
+        assert_eq!(locate(old, new, 3, 1).unwrap(), Some(2));
+    }
+}
@@ -25,3 +25,11 @@ fn base_patch_request(client: &Client, credentials: &Credentials, url: &str) ->
         .header("Authorization", format!("token {}", credentials.token))
         .header("User-Agent", "timothyandrew/gh-stack")
 }
+
+fn base_post_request(client: &Client, credentials: &Credentials, url: &str) -> RequestBuilder {
+    client
+        .post(url)
+        .timeout(Duration::from_secs(5))
+        .header("Authorization", format!("token {}", credentials.token))
+        .header("User-Agent", "timothyandrew/gh-stack")
+}
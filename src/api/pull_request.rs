@@ -20,6 +20,7 @@ pub enum PullRequestReviewState {
 #[derive(Deserialize, Debug, Clone)]
 pub struct PullRequestReview {
     state: PullRequestReviewState,
+    #[allow(dead_code)]
     body: String,
 }
 
@@ -29,12 +30,19 @@ impl PullRequestReview {
     }
 }
 
+#[derive(Deserialize, Debug, Clone)]
+pub struct PullRequestRepository {
+    full_name: String,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct PullRequestRef {
+    #[allow(dead_code)]
     label: String,
     #[serde(rename = "ref")]
     gitref: String,
     sha: String,
+    repo: Option<PullRequestRepository>,
 }
 
 #[derive(Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -47,6 +55,7 @@ pub enum PullRequestStatus {
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct PullRequest {
+    #[allow(dead_code)]
     id: usize,
     number: usize,
     head: PullRequestRef,
@@ -56,6 +65,7 @@ pub struct PullRequest {
     body: Option<String>,
     state: PullRequestStatus,
     merged_at: Option<String>,
+    merge_commit_sha: Option<String>,
     draft: bool,
     #[serde(skip)]
     reviews: Vec<PullRequestReview>,
@@ -66,10 +76,27 @@ impl PullRequest {
         &self.head.gitref
     }
 
+    pub fn head_sha(&self) -> &str {
+        &self.head.sha
+    }
+
     pub fn base(&self) -> &str {
         &self.base.gitref
     }
 
+    /// The `owner/name` of the repository this PR was opened against.
+    pub fn repository(&self) -> Option<&str> {
+        self.base.repo.as_ref().map(|repo| &repo.full_name[..])
+    }
+
+    /// Whether the head branch lives in a different repository (a fork) than the base branch.
+    pub fn is_from_fork(&self) -> bool {
+        match (&self.head.repo, &self.base.repo) {
+            (Some(head), Some(base)) => head.full_name != base.full_name,
+            _ => true,
+        }
+    }
+
     pub fn url(&self) -> &str {
         &self.url
     }
@@ -95,8 +122,18 @@ impl PullRequest {
         &self.state
     }
 
+    pub fn is_merged(&self) -> bool {
+        self.merged_at.is_some()
+    }
+
+    /// The commit GitHub created on the base branch when this PR was merged (the squash commit
+    /// for squash merges).
+    pub fn merge_commit_sha(&self) -> Option<&str> {
+        self.merge_commit_sha.as_deref()
+    }
+
     pub fn review_state(&self) -> PullRequestReviewState {
-        if !self.merged_at.is_none() {
+        if self.is_merged() {
             PullRequestReviewState::MERGED
         } else if self.at_least_one_approval() {
             PullRequestReviewState::APPROVED
@@ -140,7 +177,91 @@ pub async fn update_description(
 ) -> Result<(), Box<dyn Error>> {
     let client = reqwest::Client::new();
     let body = UpdateDescriptionRequest { body: &description };
-    let request = api::base_patch_request(&client, &c, pr.url()).json(&body);
+    let request = api::base_patch_request(&client, c, pr.url()).json(&body);
     request.send().await?;
     Ok(())
 }
+
+#[derive(Serialize, Debug)]
+struct UpdateBaseRequest<'a> {
+    base: &'a str,
+}
+
+/// Retarget `pr` so it merges into `base`.
+pub async fn update_base(
+    base: &str,
+    pr: &PullRequest,
+    c: &Credentials,
+) -> Result<(), Box<dyn Error>> {
+    let client = reqwest::Client::new();
+    let body = UpdateBaseRequest { base };
+    let request = api::base_patch_request(&client, c, pr.url()).json(&body);
+    request.send().await?.error_for_status()?;
+    Ok(())
+}
+
+#[derive(Serialize, Debug)]
+struct CreatePullRequestRequest<'a> {
+    title: &'a str,
+    head: &'a str,
+    base: &'a str,
+    body: &'a str,
+}
+
+/// Open a PR in `repository` (`owner/name`) that merges `head` into `base`.
+pub async fn create(
+    repository: &str,
+    title: &str,
+    head: &str,
+    base: &str,
+    c: &Credentials,
+) -> Result<PullRequest, Box<dyn Error>> {
+    let client = reqwest::Client::new();
+    let url = format!("https://api.github.com/repos/{}/pulls", repository);
+    let body = CreatePullRequestRequest {
+        title,
+        head,
+        base,
+        body: "",
+    };
+
+    let request = api::base_post_request(&client, c, &url).json(&body);
+    let pr = request
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<PullRequest>()
+        .await?;
+
+    Ok(pr)
+}
+
+#[derive(Serialize, Debug)]
+struct UpdateStateRequest<'a> {
+    state: &'a str,
+}
+
+/// Close `pr` without merging it.
+pub async fn close(pr: &PullRequest, c: &Credentials) -> Result<(), Box<dyn Error>> {
+    let client = reqwest::Client::new();
+    let body = UpdateStateRequest { state: "closed" };
+    let request = api::base_patch_request(&client, c, pr.url()).json(&body);
+    request.send().await?.error_for_status()?;
+    Ok(())
+}
+
+#[derive(Serialize, Debug)]
+struct CommentRequest<'a> {
+    body: &'a str,
+}
+
+/// Leave a comment on `pr` (on the conversation, not on the diff).
+pub async fn comment(body: &str, pr: &PullRequest, c: &Credentials) -> Result<(), Box<dyn Error>> {
+    let client = reqwest::Client::new();
+    // PRs are issues as far as comments are concerned
+    let url = format!("{}/comments", pr.url().replace("/pulls/", "/issues/"));
+    let body = CommentRequest { body };
+    let request = api::base_post_request(&client, c, &url).json(&body);
+    request.send().await?.error_for_status()?;
+    Ok(())
+}
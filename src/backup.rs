@@ -0,0 +1,159 @@
+use git2::{Oid, Repository};
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::git;
+use crate::remote;
+use crate::util::loop_until_confirm;
+
+const BACKUP_REF_PREFIX: &str = "refs/gh-stack/backup";
+
+/// Stack identifiers are free-form text, so anything that can't be part of a ref name is
+/// replaced.
+fn ref_safe(identifier: &str) -> String {
+    identifier
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+            _ => '-',
+        })
+        .collect()
+}
+
+fn backups_ref(identifier: &str) -> String {
+    format!("{}/{}", BACKUP_REF_PREFIX, ref_safe(identifier))
+}
+
+/// Record the current tips of `branches` (both the local branches and their remote-tracking
+/// branches on `remote`) under `refs/gh-stack/backup/<identifier>/<timestamp>/`. Returns the
+/// timestamp, which identifies the backup.
+pub fn create(
+    repo: &Repository,
+    identifier: &str,
+    remote: &str,
+    branches: &[&str],
+) -> Result<u64, Box<dyn Error>> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let prefix = format!("{}/{}", backups_ref(identifier), timestamp);
+
+    for branch in branches {
+        let local = format!("refs/heads/{}", branch);
+        let tracking = format!("refs/remotes/{}/{}", remote, branch);
+
+        for name in &[local, tracking] {
+            if let Ok(oid) = repo.refname_to_id(name) {
+                let backup = format!("{}/{}", prefix, &name["refs/".len()..]);
+                repo.reference(&backup, oid, true, "gh-stack: backup")?;
+            }
+        }
+    }
+
+    println!("Backed up the stack to {}", prefix);
+    Ok(timestamp)
+}
+
+/// Timestamps of every backup taken for `identifier`, oldest first.
+pub fn list(repo: &Repository, identifier: &str) -> Result<Vec<u64>, Box<dyn Error>> {
+    let prefix = format!("{}/", backups_ref(identifier));
+    let mut timestamps = vec![];
+
+    for reference in repo.references_glob(&format!("{}*", prefix))? {
+        let reference = reference?;
+        let name = reference.name().unwrap_or_default();
+        let timestamp = name[prefix.len()..].split('/').next().unwrap_or_default();
+
+        if let Ok(timestamp) = timestamp.parse() {
+            timestamps.push(timestamp);
+        }
+    }
+
+    timestamps.sort_unstable();
+    timestamps.dedup();
+    Ok(timestamps)
+}
+
+/// Restore local branches to the tips recorded in a backup (the latest one, unless `timestamp`
+/// is passed). With `push`, remote branches are force-pushed back to their recorded tips too.
+pub fn restore(
+    repo: &Repository,
+    identifier: &str,
+    timestamp: Option<u64>,
+    push: bool,
+) -> Result<(), Box<dyn Error>> {
+    let backups = list(repo, identifier)?;
+
+    let timestamp = match timestamp {
+        Some(timestamp) if backups.contains(&timestamp) => timestamp,
+        Some(timestamp) => {
+            return Err(format!(
+                "There's no backup {} for {} (available: {:?})",
+                timestamp, identifier, backups
+            )
+            .into())
+        }
+        None => *backups
+            .last()
+            .ok_or_else(|| format!("There are no backups for {}", identifier))?,
+    };
+
+    if git::is_dirty(repo) {
+        return Err("You have uncommitted changes; commit or stash them first.".into());
+    }
+
+    let prefix = format!("{}/{}/", backups_ref(identifier), timestamp);
+    let mut local = vec![];
+    let mut remotes = vec![];
+
+    for reference in repo.references_glob(&format!("{}*", prefix))? {
+        let reference = reference?;
+        let name = reference.name().unwrap_or_default()[prefix.len()..].to_string();
+        let oid = reference.target().unwrap();
+
+        if let Some(branch) = name.strip_prefix("heads/") {
+            local.push((branch.to_string(), oid));
+        } else if let Some(branch) = name.strip_prefix("remotes/") {
+            // `<remote>/<branch>`
+            let mut parts = branch.splitn(2, '/');
+            let remote_name = parts.next().unwrap().to_string();
+            remotes.push((remote_name, parts.next().unwrap().to_string(), oid));
+        }
+    }
+
+    println!("Restoring the backup from {}\n", timestamp);
+    for (branch, oid) in &local {
+        println!("  {} -> {}", branch, oid);
+    }
+    if push {
+        for (remote_name, branch, oid) in &remotes {
+            println!("  {}/{} -> {}", remote_name, branch, oid);
+        }
+    }
+    loop_until_confirm("Going to move these branches back ☝️ ");
+
+    for (branch, oid) in &local {
+        git::update_branch(repo, branch, &repo.find_commit(*oid)?)?;
+        println!("  + Branch {} now points to {}", branch, oid);
+    }
+
+    if push {
+        let mut leases: HashMap<&str, Vec<(&str, Oid, Oid)>> = HashMap::new();
+        for (remote_name, branch, oid) in &remotes {
+            let current =
+                repo.refname_to_id(&format!("refs/remotes/{}/{}", remote_name, branch))?;
+            leases
+                .entry(remote_name)
+                .or_default()
+                .push((branch, *oid, current));
+        }
+
+        let mut rejected = vec![];
+        for (remote_name, leases) in leases {
+            rejected.extend(remote::push_with_lease(repo, remote_name, &leases)?);
+        }
+
+        remote::check_rejected(rejected)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,177 @@
+//! `check-conflicts`: predict which branches in a stack would conflict if it were rebuilt, by
+//! replaying it the way `autorebase` would, entirely in memory. No refs (not even
+//! remote-tracking branches) and no files in the working tree are touched.
+
+use console::style;
+use git2::{BranchType, Commit, FileFavor, Index, MergeOptions, Oid, Repository, Sort, Tree};
+use std::error::Error;
+use std::path::PathBuf;
+
+use crate::api::PullRequestStatus;
+use crate::git;
+use crate::graph::FlatDep;
+use crate::rerere::{self, STAGE_MASK};
+
+/// A commit that wouldn't apply cleanly, and the files it would conflict in.
+struct Conflict<'a> {
+    commit: Commit<'a>,
+    paths: Vec<PathBuf>,
+}
+
+fn rev_to_commit<'a>(repo: &'a Repository, rev: &str) -> Result<Commit<'a>, Box<dyn Error>> {
+    Ok(repo.revparse_single(rev)?.peel_to_commit()?)
+}
+
+/// Resolve each conflict left in `index` to the commit's own version of the file ("theirs"; a
+/// file the commit deleted stays deleted).
+fn take_theirs(index: &mut Index) -> Result<(), git2::Error> {
+    let conflicts = index.conflicts()?.collect::<Result<Vec<_>, _>>()?;
+
+    for conflict in conflicts {
+        let path = match (&conflict.ancestor, &conflict.our, &conflict.their) {
+            (_, _, Some(entry)) | (_, Some(entry), None) | (Some(entry), None, None) => {
+                PathBuf::from(String::from_utf8_lossy(&entry.path).into_owned())
+            }
+            (None, None, None) => continue,
+        };
+
+        index.remove_path(&path)?;
+        if let Some(mut theirs) = conflict.their {
+            theirs.flags &= !STAGE_MASK;
+            index.add(&theirs)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Replay the commits reachable from `head` but not from `boundary` on top of `tree`, the way
+/// `autorebase` cherry-picks them (merges are picked relative to their first parent). The
+/// conflicting hunks of a conflicting commit are assumed to be resolved to the commit's own
+/// version, and everything else is merged as usual, so the rest of the stack is still checked
+/// against the new base. Returns the resulting tree and the commits that conflicted.
+fn replay<'a>(
+    repo: &'a Repository,
+    mut tree: Tree<'a>,
+    head: Oid,
+    boundary: Oid,
+) -> Result<(Tree<'a>, Vec<Conflict<'a>>), Box<dyn Error>> {
+    let mut walk = repo.revwalk()?;
+    walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+    walk.simplify_first_parent()?;
+    walk.push(head)?;
+    walk.hide(boundary)?;
+
+    let mut conflicts = vec![];
+
+    for oid in walk {
+        let commit = repo.find_commit(oid?)?;
+        let ancestor = match commit.parents().next() {
+            Some(parent) => parent.tree()?,
+            None => continue,
+        };
+
+        let mut index = repo.merge_trees(&ancestor, &tree, &commit.tree()?, None)?;
+
+        if index.has_conflicts() {
+            let paths = rerere::conflicted_paths(&index)?;
+            let mut opts = MergeOptions::new();
+            opts.file_favor(FileFavor::Theirs);
+            index = repo.merge_trees(&ancestor, &tree, &commit.tree()?, Some(&opts))?;
+            take_theirs(&mut index)?;
+            conflicts.push(Conflict { commit, paths });
+        }
+
+        tree = repo.find_tree(index.write_tree_to(repo)?)?;
+    }
+
+    Ok((tree, conflicts))
+}
+
+/// Report which branches in `stack` would conflict if it were rebuilt on `<remote>/<onto>` (the
+/// bottom PR's base by default), going by the last fetch from `remote`.
+pub fn check_conflicts(
+    stack: FlatDep,
+    repo: &Repository,
+    remote: &str,
+    boundary: Option<&str>,
+    onto: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let deps = stack
+        .iter()
+        .filter(|(dep, _)| *dep.state() == PullRequestStatus::Open)
+        .collect::<Vec<_>>();
+
+    let (pr, _) = deps.first().ok_or("There are no open PRs in this stack")?;
+
+    for (pr, _) in deps.iter() {
+        if repo.find_branch(pr.head(), BranchType::Local).is_err() {
+            return Err(format!(
+                "There's no local branch for {} (#{}); run `gh-stack checkout` first.",
+                pr.head(),
+                pr.number()
+            )
+            .into());
+        }
+    }
+
+    let old_base = rev_to_commit(repo, &format!("{}/{}", remote, pr.base()))?;
+    let base = rev_to_commit(
+        repo,
+        &format!("{}/{}", remote, onto.unwrap_or_else(|| pr.base())),
+    )?;
+    let head = rev_to_commit(repo, pr.head())?;
+
+    let mut boundary = match boundary {
+        Some(rev) => rev_to_commit(repo, rev)?.id(),
+        None => git::initial_boundary(repo, &stack, &old_base, &head),
+    };
+
+    println!(
+        "Replaying the stack on {:?} (as of the last fetch from {})",
+        base, remote
+    );
+
+    let mut tree = base.tree()?;
+    let mut conflicted = 0;
+
+    for (pr, _) in deps.iter() {
+        let head = rev_to_commit(repo, pr.head())?;
+        let (new_tree, conflicts) = replay(repo, tree, head.id(), boundary)?;
+
+        if conflicts.is_empty() {
+            println!("{}: {}", pr.head(), style("clean").green());
+        } else {
+            conflicted += 1;
+            println!("{}: {}", pr.head(), style("conflicts").red());
+
+            for conflict in conflicts {
+                let paths = conflict
+                    .paths
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect::<Vec<_>>();
+                println!(
+                    "    {} {}: {}",
+                    &conflict.commit.id().to_string()[..7],
+                    conflict.commit.summary().unwrap_or(""),
+                    paths.join(", ")
+                );
+            }
+        }
+
+        // The next branch starts where this one's remote branch ends, like in `autorebase`
+        tree = new_tree;
+        boundary = rev_to_commit(repo, &format!("{}/{}", remote, pr.head()))?.id();
+    }
+
+    if conflicted > 0 {
+        return Err(format!(
+            "Rebuilding the stack would conflict on {} branch(es)",
+            conflicted
+        )
+        .into());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,491 @@
+//! Commands that change the shape of a stack (which branches are in it, and in what order), as
+//! opposed to `autorebase`, which only moves the branches that are already there.
+
+use dialoguer::{Editor, Select};
+use git2::{BranchType, Oid, Repository, Sort};
+use std::collections::HashMap;
+use std::error::Error;
+use std::rc::Rc;
+
+use crate::api::{pull_request, PullRequest, PullRequestStatus};
+use crate::git::{self, HeadGuard, RebaseOptions};
+use crate::graph::FlatDep;
+use crate::remote;
+use crate::Credentials;
+
+/// Open PRs in the stack that merge into `branch`, directly or via other PRs, parents first.
+fn descendants<'a>(stack: &'a FlatDep, branch: &str) -> Vec<&'a Rc<PullRequest>> {
+    let mut heads = vec![branch];
+    let mut out = vec![];
+
+    for (pr, _) in stack {
+        if *pr.state() == PullRequestStatus::Open && heads.contains(&pr.base()) {
+            heads.push(pr.head());
+            out.push(pr);
+        }
+    }
+
+    out
+}
+
+/// The `(head, base)` of each PR, for `git::restack`.
+fn stacked<'a>(prs: &[&'a Rc<PullRequest>]) -> Vec<(&'a str, &'a str)> {
+    prs.iter().map(|pr| (pr.head(), pr.base())).collect()
+}
+
+fn local_tip(repo: &Repository, branch: &str) -> Result<Oid, Box<dyn Error>> {
+    repo.refname_to_id(&format!("refs/heads/{}", branch))
+        .map_err(|_| format!("There's no local branch for {}", branch).into())
+}
+
+/// Add `branch` to the stack between `after` and the PR that currently merges into `after`.
+/// The branch is created at the tip of `after` (or reused, if it already exists and is based on
+/// `after`), everything above it is restacked onto it, and the child PR is retargeted at it.
+/// With a `title`, a PR is opened for the new branch too.
+///
+/// Running it again once `branch` has commits of its own (GitHub won't open a PR for an empty
+/// branch) restacks the PRs that were retargeted at `branch` onto those commits, and opens the PR.
+#[allow(clippy::too_many_arguments)]
+pub async fn insert(
+    identifier: &str,
+    stack: FlatDep,
+    repo: &Repository,
+    remote: &str,
+    after: &str,
+    branch: &str,
+    title: Option<&str>,
+    credentials: &Credentials,
+) -> Result<(), Box<dyn Error>> {
+    if git::is_dirty(repo) {
+        return Err("You have uncommitted changes; commit or stash them first.".into());
+    }
+
+    // An earlier run already retargeted the child PRs at `branch`, but didn't open a PR for it
+    let rerun = stack.iter().all(|(pr, _)| pr.head() != branch)
+        && stack
+            .iter()
+            .any(|(pr, _)| *pr.state() == PullRequestStatus::Open && pr.base() == branch);
+    let base = if rerun { branch } else { after };
+
+    let descendants = descendants(&stack, base);
+    if descendants.is_empty() {
+        return Err(format!("No open PR in the stack merges into {}", after).into());
+    }
+
+    let _head = HeadGuard::new(repo);
+    let parent_tip = local_tip(repo, after)?;
+
+    let tip = match repo.find_branch(branch, BranchType::Local) {
+        Ok(existing) => {
+            let tip = existing.get().peel_to_commit()?;
+            if !git::is_ancestor(repo, parent_tip, tip.id()) {
+                return Err(format!(
+                    "Branch {} already exists, and isn't based on {}",
+                    branch, after
+                )
+                .into());
+            }
+            println!("Using the existing branch {} at {}", branch, tip.id());
+            tip
+        }
+        Err(_) if rerun => {
+            return Err(format!(
+                "PRs in the stack merge into {}, but there's no local branch for it",
+                branch
+            )
+            .into())
+        }
+        Err(_) => git::create_branch_at(repo, branch, after)?,
+    };
+
+    // Leases are taken against the remote branches the local ones were built on
+    let mut heads = descendants.iter().map(|pr| pr.head()).collect::<Vec<_>>();
+    heads.push(base);
+    let previous = remote::fetch(repo, remote, &heads)?;
+
+    // The descendants were built on top of `after`; after an earlier run, on top of `branch` as
+    // it was pushed back then
+    if rerun && previous[branch].is_zero() {
+        return Err(format!("{} was never pushed to {}", branch, remote).into());
+    }
+    let old_tip = git::restack_boundary(repo, &previous, base)?;
+
+    let mut new_tips = vec![(branch, tip.clone())];
+
+    // A new branch starts out at the tip of `after`, so there's nothing to restack until it has
+    // commits of its own
+    if tip.id() != old_tip {
+        new_tips.extend(git::restack(
+            repo,
+            &stacked(&descendants),
+            &previous,
+            base,
+            old_tip,
+            tip.clone(),
+        )?);
+    }
+
+    git::publish_branches(identifier, repo, remote, &new_tips, &previous, true)?;
+
+    if !rerun {
+        println!();
+        for pr in descendants.iter().filter(|pr| pr.base() == after) {
+            pull_request::update_base(branch, pr, credentials).await?;
+            println!("  + #{} now merges into {}", pr.number(), branch);
+        }
+    }
+
+    if let Some(title) = title {
+        if tip.id() == parent_tip {
+            println!(
+                "\n{} has no commits yet, so GitHub won't open a PR for it. Commit to it and run `gh-stack insert` again.",
+                branch
+            );
+            return Ok(());
+        }
+
+        let repository = stack
+            .iter()
+            .find_map(|(pr, _)| pr.repository())
+            .ok_or("Couldn't tell which repository the stack belongs to")?;
+
+        // The identifier is what makes the new PR part of the stack
+        let title = if title.contains(identifier) {
+            title.to_string()
+        } else {
+            format!("{} {}", identifier, title)
+        };
+
+        let pr = pull_request::create(repository, &title, branch, after, credentials).await?;
+        println!("  + Opened #{}: {}", pr.number(), pr.title());
+    }
+
+    Ok(())
+}
+
+/// Let the user reorder the open PRs in the stack in `$EDITOR` (like `git rebase -i`), rebuild
+/// the branches in the new order on top of the trunk, and retarget every PR whose base changed.
+/// Returns `false` if the order didn't change.
+pub async fn reorder(
+    identifier: &str,
+    stack: &FlatDep,
+    repo: &Repository,
+    remote: &str,
+    credentials: &Credentials,
+) -> Result<bool, Box<dyn Error>> {
+    if git::is_dirty(repo) {
+        return Err("You have uncommitted changes; commit or stash them first.".into());
+    }
+
+    let prs = stack
+        .iter()
+        .filter(|(pr, _)| *pr.state() == PullRequestStatus::Open)
+        .map(|(pr, _)| pr)
+        .collect::<Vec<_>>();
+    let trunk = match prs.first() {
+        Some(pr) => pr.base(),
+        None => return Err("There are no open PRs in the stack".into()),
+    };
+
+    // Reordering only makes sense for a single chain of PRs, each merging into the one before
+    for pair in prs.windows(2) {
+        if pair[1].base() != pair[0].head() {
+            return Err(format!(
+                "#{} merges into {} rather than {}; only stacks that form a single chain can be reordered",
+                pair[1].number(),
+                pair[1].base(),
+                pair[0].head()
+            )
+            .into());
+        }
+    }
+
+    let mut text = String::new();
+    for pr in &prs {
+        text.push_str(&format!("{} #{} {}\n", pr.head(), pr.number(), pr.title()));
+    }
+    text.push_str(&format!(
+        "\n# Reorder the branches in {} (the first one merges into {}).\n\
+         # Lines starting with `#` are ignored; every branch has to stay in the stack.\n",
+        identifier, trunk
+    ));
+
+    let edited = Editor::new()
+        .edit(&text)?
+        .ok_or("The new order wasn't saved; leaving the stack alone")?;
+    let order = edited
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.split_whitespace().next().unwrap())
+        .collect::<Vec<_>>();
+
+    let current = prs.iter().map(|pr| pr.head()).collect::<Vec<_>>();
+    let (mut before, mut after) = (current.clone(), order.clone());
+    before.sort_unstable();
+    after.sort_unstable();
+    if before != after {
+        return Err("The new order has to contain every branch in the stack exactly once".into());
+    }
+    if order == current {
+        println!("The order of the stack didn't change.");
+        return Ok(false);
+    }
+
+    let _head = HeadGuard::new(repo);
+
+    let mut branches = current.clone();
+    branches.push(trunk);
+    let previous = remote::fetch(repo, remote, &branches)?;
+
+    let base =
+        repo.find_commit(repo.refname_to_id(&format!("refs/remotes/{}/{}", remote, trunk))?)?;
+    let bottom = repo.find_commit(local_tip(repo, current[0])?)?;
+
+    // A branch's own commits are the ones on top of the branch it's currently based on
+    let mut boundaries = HashMap::new();
+    boundaries.insert(
+        current[0],
+        git::initial_boundary(repo, stack, &base, &bottom),
+    );
+    for pair in current.windows(2) {
+        boundaries.insert(pair[1], local_tip(repo, pair[0])?);
+    }
+
+    let branches = order
+        .iter()
+        .map(|branch| (*branch, boundaries[branch]))
+        .collect::<Vec<_>>();
+    let options = RebaseOptions::default();
+
+    println!("Rebuilding the stack on {:?}", base);
+    let new_tips = git::replay_branches(repo, base, &branches, options);
+    git::publish_branches(identifier, repo, remote, &new_tips, &previous, true)?;
+
+    println!();
+    let mut new_base = trunk;
+    for branch in order {
+        let pr = prs.iter().find(|pr| pr.head() == branch).unwrap();
+        if pr.base() != new_base {
+            pull_request::update_base(new_base, pr, credentials).await?;
+            println!("  + #{} now merges into {}", pr.number(), new_base);
+        }
+        new_base = branch;
+    }
+
+    Ok(true)
+}
+
+/// Fold `branch` into the branch it's based on: its commits are added to the parent branch,
+/// everything above it is restacked onto the parent, PRs that merged into `branch` are
+/// retargeted at the parent, and the PR for `branch` is closed with a comment pointing at the
+/// parent's PR.
+pub async fn fold(
+    identifier: &str,
+    stack: FlatDep,
+    repo: &Repository,
+    remote: &str,
+    branch: &str,
+    credentials: &Credentials,
+) -> Result<(), Box<dyn Error>> {
+    if git::is_dirty(repo) {
+        return Err("You have uncommitted changes; commit or stash them first.".into());
+    }
+
+    let open = stack
+        .iter()
+        .filter(|(pr, _)| *pr.state() == PullRequestStatus::Open)
+        .map(|(pr, _)| pr)
+        .collect::<Vec<_>>();
+    let folded = open
+        .iter()
+        .find(|pr| pr.head() == branch)
+        .ok_or_else(|| format!("There's no open PR in the stack for {}", branch))?;
+    let parent = open
+        .iter()
+        .find(|pr| pr.head() == folded.base())
+        .ok_or_else(|| {
+            format!(
+                "{} merges into {}, which isn't part of the stack; there's nothing to fold it into",
+                branch,
+                folded.base()
+            )
+        })?;
+    let descendants = descendants(&stack, branch);
+
+    let _head = HeadGuard::new(repo);
+
+    let mut heads = descendants.iter().map(|pr| pr.head()).collect::<Vec<_>>();
+    heads.push(parent.head());
+    heads.push(branch);
+    let previous = remote::fetch(repo, remote, &heads)?;
+
+    let options = RebaseOptions::default();
+
+    let parent_tip = repo.find_commit(local_tip(repo, parent.head())?)?;
+    let branch_tip = repo.find_commit(local_tip(repo, branch)?)?;
+
+    // Usually the branch is already on top of its parent, and the parent can just be
+    // fast-forwarded to it
+    let new_parent_tip = if git::is_ancestor(repo, parent_tip.id(), branch_tip.id()) {
+        branch_tip.clone()
+    } else {
+        let branches = [(
+            branch,
+            git::restack_boundary(repo, &previous, parent.head())?,
+        )];
+        let (_, tip) = git::replay_branches(repo, parent_tip.clone(), &branches, options)
+            .pop()
+            .unwrap();
+        tip
+    };
+
+    let mut new_tips = vec![(parent.head(), new_parent_tip.clone())];
+
+    let old_tip = git::restack_boundary(repo, &previous, branch)?;
+    if new_parent_tip.id() != old_tip {
+        new_tips.extend(git::restack(
+            repo,
+            &stacked(&descendants),
+            &previous,
+            branch,
+            old_tip,
+            new_parent_tip,
+        )?);
+    }
+
+    git::publish_branches(identifier, repo, remote, &new_tips, &previous, true)?;
+
+    println!();
+    for pr in descendants.iter().filter(|pr| pr.base() == branch) {
+        pull_request::update_base(parent.head(), pr, credentials).await?;
+        println!("  + #{} now merges into {}", pr.number(), parent.head());
+    }
+
+    let comment = format!("Folded into #{}.", parent.number());
+    pull_request::comment(&comment, folded, credentials).await?;
+    pull_request::close(folded, credentials).await?;
+    println!("  + Closed #{}", folded.number());
+
+    println!(
+        "\nThe branch {} wasn't deleted (locally or on {}); delete it once you're done with it.",
+        branch, remote
+    );
+
+    Ok(())
+}
+
+/// Split `branch` in two: a new branch `lower` is created at `at` (picked interactively when
+/// not passed), a PR is opened for it against `branch`'s old base, and `branch`'s PR is
+/// retargeted at it. `branch` already contains `lower`, so nothing has to be restacked.
+#[allow(clippy::too_many_arguments)]
+pub async fn split(
+    identifier: &str,
+    stack: FlatDep,
+    repo: &Repository,
+    remote: &str,
+    branch: &str,
+    lower: &str,
+    at: Option<&str>,
+    title: Option<&str>,
+    credentials: &Credentials,
+) -> Result<(), Box<dyn Error>> {
+    let pr = stack
+        .iter()
+        .map(|(pr, _)| pr)
+        .find(|pr| *pr.state() == PullRequestStatus::Open && pr.head() == branch)
+        .ok_or_else(|| format!("There's no open PR in the stack for {}", branch))?;
+
+    if repo.find_branch(lower, BranchType::Local).is_ok() {
+        return Err(format!("Branch {} already exists", lower).into());
+    }
+
+    // The commits in the PR, oldest first. A stale local copy of the base would let its
+    // commits pass for the PR's own, so the walk stops at the base branch on the remote too.
+    remote::fetch(repo, remote, &[pr.base()])?;
+    let base = repo.refname_to_id(&format!("refs/remotes/{}/{}", remote, pr.base()))?;
+    let mut walk = repo.revwalk()?;
+    walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+    walk.simplify_first_parent()?;
+    walk.push(local_tip(repo, branch)?)?;
+    walk.hide(base)?;
+    if let Ok(local_base) = local_tip(repo, pr.base()) {
+        walk.hide(local_base)?;
+    }
+    let commits = walk
+        .map(|oid| repo.find_commit(oid?))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Splitting at the tip would leave nothing for the original PR
+    let candidates = &commits[..commits.len().saturating_sub(1)];
+    if candidates.is_empty() {
+        return Err(format!("{} only has one commit; there's nothing to split", branch).into());
+    }
+
+    let split_at = match at {
+        Some(rev) => {
+            let oid = repo.revparse_single(rev)?.peel_to_commit()?.id();
+            candidates
+                .iter()
+                .find(|commit| commit.id() == oid)
+                .ok_or_else(|| {
+                    format!(
+                        "{} isn't one of the commits in {} (or is its tip)",
+                        rev, branch
+                    )
+                })?
+        }
+        None => {
+            let items = candidates
+                .iter()
+                .map(|commit| {
+                    format!(
+                        "{:.7} {}",
+                        commit.id(),
+                        commit.summary().unwrap_or_default()
+                    )
+                })
+                .collect::<Vec<_>>();
+            let picked = Select::new()
+                .with_prompt(format!("Last commit that goes into {}", lower))
+                .items(&items)
+                .default(0)
+                .interact()?;
+            &candidates[picked]
+        }
+    };
+
+    let tip = repo
+        .branch(lower, split_at, false)?
+        .get()
+        .peel_to_commit()?;
+    println!("  + Created branch {} at {}", lower, tip.id());
+    git::publish_branches(
+        identifier,
+        repo,
+        remote,
+        &[(lower, tip.clone())],
+        &HashMap::new(),
+        true,
+    )?;
+
+    let repository = pr
+        .repository()
+        .ok_or("Couldn't tell which repository the stack belongs to")?;
+
+    // The identifier is what makes the new PR part of the stack
+    let title = match title {
+        Some(title) if title.contains(identifier) => title.to_string(),
+        Some(title) => format!("{} {}", identifier, title),
+        None => format!("{} {}", identifier, tip.summary().unwrap_or(lower)),
+    };
+
+    println!();
+    let new_pr = pull_request::create(repository, &title, lower, pr.base(), credentials).await?;
+    println!("  + Opened #{}: {}", new_pr.number(), new_pr.title());
+
+    pull_request::update_base(lower, pr, credentials).await?;
+    println!("  + #{} now merges into {}", pr.number(), lower);
+
+    Ok(())
+}
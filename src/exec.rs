@@ -0,0 +1,253 @@
+//! `exec`: run a command on every branch in a stack, and report which ones it failed on.
+
+use console::style;
+use git2::build::CheckoutBuilder;
+use git2::Repository;
+use std::error::Error;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus};
+
+use crate::api::PullRequestStatus;
+use crate::git::{self, HeadGuard};
+use crate::graph::FlatDep;
+
+pub struct ExecOptions {
+    /// Don't run the command on any more branches once it fails on one
+    pub fail_fast: bool,
+    /// Check every branch out into its own worktree, and run the command on all of them at once
+    pub worktrees: bool,
+}
+
+enum Outcome {
+    Passed,
+    Failed(ExitStatus),
+    Skipped,
+}
+
+fn run(command: &[&str], dir: &Path) -> Result<Command, Box<dyn Error>> {
+    let (program, args) = command.split_first().ok_or("No command to run")?;
+    let mut command = Command::new(program);
+    command.args(args).current_dir(dir);
+    Ok(command)
+}
+
+fn outcome(status: ExitStatus) -> Outcome {
+    if status.success() {
+        Outcome::Passed
+    } else {
+        Outcome::Failed(status)
+    }
+}
+
+/// Check out each branch in turn in the repository's own working tree.
+fn run_in_place(
+    repo: &Repository,
+    branches: &[&str],
+    command: &[&str],
+    options: &ExecOptions,
+) -> Result<Vec<Outcome>, Box<dyn Error>> {
+    if git::is_dirty(repo) {
+        return Err(
+            "You have uncommitted changes; commit or stash them first (or pass `--worktrees`)."
+                .into(),
+        );
+    }
+
+    let _head = HeadGuard::new(repo);
+    let workdir = repo
+        .workdir()
+        .ok_or("The repository doesn't have a working tree")?;
+    let mut outcomes = vec![];
+
+    for branch in branches {
+        if options.fail_fast && outcomes.iter().any(|o| matches!(o, Outcome::Failed(_))) {
+            outcomes.push(Outcome::Skipped);
+            continue;
+        }
+
+        let refname = format!("refs/heads/{}", branch);
+        let commit = repo.find_reference(&refname)?.peel_to_commit()?;
+        let mut cb = CheckoutBuilder::new();
+        cb.safe();
+        repo.checkout_tree(commit.as_object(), Some(&mut cb))?;
+        repo.set_head(&refname)?;
+
+        println!("\n{}", style(format!("Running on {}", branch)).bold());
+        outcomes.push(outcome(run(command, workdir)?.status()?));
+    }
+
+    Ok(outcomes)
+}
+
+fn git(repo: &Repository, args: &[&str]) -> Result<(), Box<dyn Error>> {
+    let status = Command::new("git")
+        .arg("--git-dir")
+        .arg(repo.path())
+        .args(args)
+        .status()?;
+    if !status.success() {
+        return Err(format!("`git {}` failed", args.join(" ")).into());
+    }
+    Ok(())
+}
+
+/// Check every branch out into its own (detached) worktree under `.git/gh-stack/`, and run the
+/// command on all of them at once. Output goes to a log file per branch.
+fn run_in_worktrees(
+    repo: &Repository,
+    branches: &[&str],
+    command: &[&str],
+    options: &ExecOptions,
+) -> Result<Vec<Outcome>, Box<dyn Error>> {
+    let root = repo.path().join("gh-stack").join("exec");
+    fs::create_dir_all(&root)?;
+
+    let mut worktrees: Vec<PathBuf> = vec![];
+    let mut children: Vec<Child> = vec![];
+
+    // Whatever happens, nothing is left running and every worktree that was added is removed,
+    // so the next run doesn't trip over them
+    let outcomes = start(
+        repo,
+        &root,
+        branches,
+        command,
+        &mut worktrees,
+        &mut children,
+    )
+    .and_then(|()| wait(std::mem::take(&mut children), options));
+
+    for mut child in children {
+        child.kill().ok();
+        child.wait().ok();
+    }
+
+    let mut removed = Ok(());
+    for path in worktrees {
+        let result = git(
+            repo,
+            &["worktree", "remove", "--force", &path.to_string_lossy()],
+        );
+        removed = removed.and(result);
+    }
+
+    let outcomes = outcomes?;
+    removed?;
+    Ok(outcomes)
+}
+
+/// Add a worktree for every branch and start the command in it, recording each worktree and
+/// process as soon as it exists.
+fn start(
+    repo: &Repository,
+    root: &Path,
+    branches: &[&str],
+    command: &[&str],
+    worktrees: &mut Vec<PathBuf>,
+    children: &mut Vec<Child>,
+) -> Result<(), Box<dyn Error>> {
+    for branch in branches {
+        let name = branch.replace('/', "-");
+        let path = root.join(&name);
+        let sha = repo
+            .refname_to_id(&format!("refs/heads/{}", branch))?
+            .to_string();
+
+        git(
+            repo,
+            &[
+                "worktree",
+                "add",
+                "--detach",
+                "--force",
+                &path.to_string_lossy(),
+                &sha,
+            ],
+        )?;
+        worktrees.push(path.clone());
+
+        let log = root.join(format!("{}.log", name));
+        println!("Running on {} (output in {})", branch, log.display());
+        let mut command = run(command, &path)?;
+        let log = File::create(&log)?;
+        command.stdout(log.try_clone()?).stderr(log);
+        children.push(command.spawn()?);
+    }
+
+    Ok(())
+}
+
+/// Wait for every command in turn (killing the rest once one fails, with `fail_fast`).
+fn wait(children: Vec<Child>, options: &ExecOptions) -> Result<Vec<Outcome>, Box<dyn Error>> {
+    let mut outcomes = vec![];
+    let mut failed = false;
+    for mut child in children {
+        if failed && options.fail_fast {
+            child.kill().ok();
+            child.wait()?;
+            outcomes.push(Outcome::Skipped);
+            continue;
+        }
+
+        let result = outcome(child.wait()?);
+        failed |= matches!(result, Outcome::Failed(_));
+        outcomes.push(result);
+    }
+
+    Ok(outcomes)
+}
+
+/// Run `command` on every open branch in the stack, in stack order, and print a summary.
+pub fn exec(
+    stack: FlatDep,
+    repo: &Repository,
+    command: &[&str],
+    options: ExecOptions,
+) -> Result<(), Box<dyn Error>> {
+    let branches = stack
+        .iter()
+        .filter(|(pr, _)| *pr.state() == PullRequestStatus::Open)
+        .map(|(pr, _)| pr.head())
+        .collect::<Vec<_>>();
+
+    for branch in &branches {
+        if repo.find_branch(branch, git2::BranchType::Local).is_err() {
+            return Err(format!(
+                "There's no local branch for {}; run `gh-stack checkout` first.",
+                branch
+            )
+            .into());
+        }
+    }
+
+    let outcomes = if options.worktrees {
+        run_in_worktrees(repo, &branches, command, &options)?
+    } else {
+        run_in_place(repo, &branches, command, &options)?
+    };
+
+    let width = branches
+        .iter()
+        .map(|b| b.len())
+        .fold("Branch".len(), usize::max);
+    println!("\n{:width$}  Result", "Branch", width = width);
+    for (branch, outcome) in branches.iter().zip(&outcomes) {
+        let result = match outcome {
+            Outcome::Passed => style("passed".to_string()).green(),
+            Outcome::Failed(status) => style(format!("failed ({})", status)).red(),
+            Outcome::Skipped => style("skipped".to_string()).dim(),
+        };
+        println!("{:width$}  {}", branch, result, width = width);
+    }
+
+    let failures = outcomes
+        .iter()
+        .filter(|o| matches!(o, Outcome::Failed(_)))
+        .count();
+    if failures > 0 {
+        return Err(format!("The command failed on {} branch(es)", failures).into());
+    }
+
+    Ok(())
+}
@@ -1,23 +1,38 @@
-use crate::api::PullRequestStatus;
+use crate::api::{PullRequest, PullRequestStatus};
+use crate::backup;
 use crate::graph::FlatDep;
+use crate::remote;
+use crate::rerere;
 use crate::util::loop_until_confirm;
+use console::style;
 use git2::build::CheckoutBuilder;
-use git2::{CherrypickOptions, Commit, Index, Oid, Repository, Revwalk, Sort};
+use git2::{
+    BranchType, CherrypickOptions, Commit, Index, Oid, Repository, RepositoryState, Revwalk, Sort,
+    Status, StatusOptions,
+};
 
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use tokio::process::Command;
+use std::process::Command;
 
 fn remote_ref(remote: &str, git_ref: &str) -> String {
     format!("{}/{}", remote, git_ref)
 }
 
-/// For all open pull requests in the graph, generate a series of commands
-/// (force-pushes) that will rebase the entire stack. The "PREBASE" variable
-/// is a base for the first branch in the stack (essentially a "stop cherry-picking
-/// here" marker), and is required because of our squash-merge workflow.
+/// For all open pull requests in the graph, generate a bash script that rebuilds the stack:
+/// each branch is cherry-picked onto its (rebuilt) parent and force-pushed to `remote`, parents
+/// first, so branched stacks work too. Branches at the bottom of the stack are rebuilt onto
+/// `trunk` (their PR's base, by default). A bottom branch's commits start after its "PREBASE"
+/// marker, which is required because of our squash-merge workflow; it's filled in when it can
+/// be worked out (from a merged PR below it, or from `repo` when one is passed).
 /// TODO: Move this directly into Rust.
-pub fn generate_rebase_script(deps: FlatDep) -> String {
-    let deps = deps
+pub fn generate_rebase_script(
+    stack: FlatDep,
+    remote: &str,
+    trunk: Option<&str>,
+    repo: Option<&Repository>,
+) -> String {
+    let deps = stack
         .iter()
         .filter(|(dep, _)| *dep.state() == PullRequestStatus::Open)
         .collect::<Vec<_>>();
@@ -28,50 +43,132 @@ pub fn generate_rebase_script(deps: FlatDep) -> String {
     out.push_str("set -euo pipefail\n");
     out.push_str("set -o xtrace\n\n");
 
-    out.push_str("# ------ THIS SCRIPT ASSUMES YOUR PR STACK IS A SINGLE CHAIN WITHOUT BRANCHING ----- #\n\n");
-    out.push_str("# It starts at the base of the stack, cherry-picking onto the new base and force-pushing as it goes.\n");
-    out.push_str("# We can't tell where the initial cherry-pick should stop (mainly because of our squash merge workflow),\n");
-    out.push_str(
-        "# so that initial stopping point for the first PR needs to be specified manually.\n\n",
-    );
-
-    out.push_str("export PREBASE=\"<enter a marker to stop the initial cherry-pick at>\"\n");
+    out.push_str(&format!("# It starts at the bottom of the stack, cherry-picking each branch onto its rebuilt parent and force-pushing it to `{}` as it goes.\n\n", remote));
+    out.push_str(&format!("git fetch {}\n\n", remote));
 
-    for (from, to) in deps {
-        let to = if let Some(pr) = to {
-            pr.head().to_string()
-        } else {
-            String::from("<enter a ref to rebase the stack on; usually `develop`>")
-        };
+    out.push_str("# Where every branch is before anything is rewritten\n");
+    for (pr, _) in deps.iter() {
+        out.push_str(&format!(
+            "export OLD_{}=\"$(git rev-parse --verify {})\"\n",
+            pr.number(),
+            remote_ref(remote, pr.head())
+        ));
+    }
 
+    for (pr, parent) in deps.iter() {
+        let n = pr.number();
         out.push_str("\n# -------------- #\n\n");
 
-        out.push_str(&format!("export TO=\"{}\"\n", remote_ref("heap", &to)));
+        match parent {
+            Some(parent) if *parent.state() == PullRequestStatus::Open => {
+                out.push_str(&format!(
+                    "# #{} ({}), onto #{} ({})\n",
+                    n,
+                    pr.head(),
+                    parent.number(),
+                    parent.head()
+                ));
+                out.push_str(&format!(
+                    "git checkout --detach \"$NEW_{}\"\n",
+                    parent.number()
+                ));
+                out.push_str(&script_cherry_pick(
+                    &format!("$OLD_{}", parent.number()),
+                    &format!("$OLD_{}", n),
+                ));
+            }
+            _ => {
+                let onto = remote_ref(remote, trunk.unwrap_or_else(|| pr.base()));
+                out.push_str(&format!("# #{} ({}), onto {}\n", n, pr.head(), onto));
+                out.push_str(&script_prebase(&stack, pr, parent.as_ref(), remote, repo));
+                out.push_str(&format!("git checkout --detach \"{}\"\n", onto));
+                out.push_str(&script_cherry_pick(
+                    &format!("$PREBASE_{}", n),
+                    &format!("$OLD_{}", n),
+                ));
+            }
+        }
+
         out.push_str(&format!(
-            "export FROM=\"{}\"\n\n",
-            remote_ref("heap", from.head())
+            "export NEW_{}=\"$(git rev-parse --verify HEAD)\"\n",
+            n
+        ));
+        out.push_str(&format!(
+            "git push -f {} HEAD:refs/heads/{}\n",
+            remote,
+            pr.head()
         ));
-
-        out.push_str("git checkout \"$TO\"\n");
-        out.push_str("git cherry-pick \"$PREBASE\"..\"$FROM\"\n");
-        out.push_str("export PREBASE=\"$(git rev-parse --verify $FROM)\"\n");
-        out.push_str("git push -f heap HEAD:refs/heads/\"$FROM\"\n");
     }
 
     out
 }
 
-fn oid_to_commit(repo: &Repository, oid: Oid) -> Commit {
-    repo.find_commit(oid).unwrap()
+/// Cherry-pick `from..to` in the script. `git cherry-pick` fails on an empty range, which would
+/// abort the script (`set -e`) on a branch with no commits of its own, so that's skipped.
+fn script_cherry_pick(from: &str, to: &str) -> String {
+    format!(
+        "if [ -n \"$(git rev-list \"{0}\"..\"{1}\")\" ]; then git cherry-pick \"{0}\"..\"{1}\"; fi\n",
+        from, to
+    )
 }
 
-fn head_commit(repo: &Repository) -> Commit {
-    repo.find_commit(repo.head().unwrap().target().unwrap())
-        .unwrap()
+/// The `PREBASE_<number>` line for a PR at the bottom of the stack: where its commits start.
+fn script_prebase(
+    stack: &FlatDep,
+    pr: &PullRequest,
+    parent: Option<&std::rc::Rc<PullRequest>>,
+    remote: &str,
+    repo: Option<&Repository>,
+) -> String {
+    let n = pr.number();
+
+    // If the PR below has been (squash-)merged, its head is exactly where the initial
+    // cherry-pick needs to stop
+    if let Some(parent) = parent.filter(|parent| parent.is_merged()) {
+        return format!(
+            "# The initial cherry-pick stops at the head of the merged PR #{}.\nexport PREBASE_{}=\"{}\"\n",
+            parent.number(),
+            n,
+            parent.head_sha()
+        );
+    }
+
+    let boundary = repo.and_then(|repo| {
+        let base = repo
+            .revparse_single(&remote_ref(remote, pr.base()))
+            .ok()?
+            .peel_to_commit()
+            .ok()?;
+        let head = repo
+            .revparse_single(&remote_ref(remote, pr.head()))
+            .ok()?
+            .peel_to_commit()
+            .ok()?;
+        Some(initial_boundary(repo, stack, &base, &head))
+    });
+
+    match boundary {
+        Some(boundary) => format!(
+            "# The initial cherry-pick stops at the last commit that's already in {}.\nexport PREBASE_{}=\"{}\"\n",
+            pr.base(),
+            n,
+            boundary
+        ),
+        None => format!(
+            "# We can't tell where the initial cherry-pick should stop (mainly because of our squash merge workflow),\n\
+             # so that initial stopping point needs to be specified manually (or pass `--repo`).\n\
+             export PREBASE_{}=\"<enter a marker to stop the initial cherry-pick at>\"\n",
+            n
+        ),
+    }
+}
+
+fn oid_to_commit(repo: &Repository, oid: Oid) -> Commit<'_> {
+    repo.find_commit(oid).unwrap()
 }
 
 fn checkout_commit(repo: &Repository, commit: &Commit, options: Option<&mut CheckoutBuilder>) {
-    repo.checkout_tree(&commit.as_object(), options).unwrap();
+    repo.checkout_tree(commit.as_object(), options).unwrap();
     repo.set_head_detached(commit.id()).unwrap();
 }
 
@@ -81,145 +178,1589 @@ fn rev_to_commit<'a>(repo: &'a Repository, rev: &str) -> Commit<'a> {
     commit
 }
 
-/// Commit and checkout `index`
-fn create_commit<'a>(repo: &'a Repository, index: &mut Index, message: &str) -> Commit<'a> {
-    let tree = index.write_tree_to(&repo).unwrap();
+fn sha_to_commit<'a>(repo: &'a Repository, sha: &str) -> Option<Commit<'a>> {
+    let oid = Oid::from_str(sha).ok()?;
+    repo.find_commit(oid).ok()
+}
+
+pub fn is_ancestor(repo: &Repository, ancestor: Oid, commit: Oid) -> bool {
+    ancestor == commit || repo.graph_descendant_of(commit, ancestor).unwrap()
+}
+
+/// If a PR in the stack below `head` has already been merged into `base`, the initial
+/// cherry-pick needs to stop at that PR's head. Uses the head and merge commit SHAs
+/// reported by the API, so this works for squash merges too.
+fn merged_pr_boundary(
+    repo: &Repository,
+    stack: &FlatDep,
+    base: &Commit,
+    head: &Commit,
+) -> Option<Oid> {
+    let candidates = stack
+        .iter()
+        .filter(|(pr, _)| pr.is_merged())
+        .filter_map(|(pr, _)| {
+            let merge_commit = sha_to_commit(repo, pr.merge_commit_sha()?)?;
+            let pr_head = sha_to_commit(repo, pr.head_sha())?;
+
+            if is_ancestor(repo, merge_commit.id(), base.id())
+                && is_ancestor(repo, pr_head.id(), head.id())
+            {
+                Some(pr_head.id())
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // The topmost merged PR is the one that isn't an ancestor of any of the others.
+    candidates.iter().cloned().find(|&candidate| {
+        candidates
+            .iter()
+            .all(|&other| other == candidate || !is_ancestor(repo, candidate, other))
+    })
+}
+
+fn patch_id(repo: &Repository, from: &Commit, to: &Commit) -> Option<Oid> {
+    let diff = repo
+        .diff_tree_to_tree(Some(&from.tree().unwrap()), Some(&to.tree().unwrap()), None)
+        .unwrap();
+
+    if diff.deltas().len() == 0 {
+        return None;
+    }
+
+    Some(diff.patchid(None).unwrap())
+}
+
+/// Find the last commit on `head` whose changes have already landed on `base`, by comparing
+/// patch-ids. A squash commit on `base` has the same patch-id as the combined diff of the
+/// commits it squashed, so the combined diff of every prefix of `head` is compared against
+/// each commit on `base`. Falls back to the merge base of the two commits.
+fn patch_id_boundary(repo: &Repository, base: &Commit, head: &Commit) -> Oid {
+    let merge_base = repo.merge_base(base.id(), head.id()).unwrap();
+
+    let mut walk = repo.revwalk().unwrap();
+    walk.push(base.id()).unwrap();
+    walk.hide(merge_base).unwrap();
+
+    let upstream = walk
+        .map(|oid| oid_to_commit(repo, oid.unwrap()))
+        .filter(|commit| commit.parent_count() == 1)
+        .filter_map(|commit| patch_id(repo, &commit.parent(0).unwrap(), &commit))
+        .collect::<HashSet<_>>();
+
+    let mut walk = repo.revwalk().unwrap();
+    walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE).unwrap();
+    walk.simplify_first_parent().unwrap();
+    walk.push(head.id()).unwrap();
+    walk.hide(merge_base).unwrap();
+
+    let mut boundary = oid_to_commit(repo, merge_base);
+
+    for commit in walk {
+        let commit = oid_to_commit(repo, commit.unwrap());
+
+        if let Some(id) = patch_id(repo, &boundary, &commit) {
+            if upstream.contains(&id) {
+                boundary = commit;
+            }
+        }
+    }
+
+    boundary.id()
+}
+
+/// Figure out where the initial cherry-pick should stop: the head of a merged PR lower down
+/// in the stack if there is one, otherwise the last commit whose changes are already upstream.
+pub fn initial_boundary(repo: &Repository, stack: &FlatDep, base: &Commit, head: &Commit) -> Oid {
+    merged_pr_boundary(repo, stack, base, head)
+        .unwrap_or_else(|| patch_id_boundary(repo, base, head))
+}
+
+/// How merge commits found in a branch are dealt with while rebuilding the stack.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum MergeStrategy {
+    /// Refuse to continue.
+    #[default]
+    Abort,
+    /// Skip merges whose other parents are already contained in the new base (trunk merges).
+    Drop,
+    /// Recreate merges on top of the rewritten first parent (like `git rebase --rebase-merges`).
+    Rebase,
+}
+
+/// Options that control how `perform_rebase` rebuilds (and publishes) the stack.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RebaseOptions {
+    pub merges: MergeStrategy,
+    /// Keep commits that become empty because their changes are already part of the new base
+    pub keep_empty: bool,
+    /// Squash `fixup!`/`squash!` commits into the commits they target in the same branch
+    pub autosquash: bool,
+    /// Launch `git mergetool` for every conflicted path
+    pub mergetool: bool,
+    /// Record how conflicts were resolved, and reuse those resolutions when they come up again
+    pub rerere: bool,
+    /// Push the rebuilt stack to the remote; otherwise only local branches are updated
+    pub push: bool,
+}
+
+/// Write `index` out as a commit on top of `parents`, without checking anything out. If the
+/// working tree was used to resolve conflicts, `HEAD` is moved along to the new commit.
+fn create_commit<'a>(
+    repo: &'a Repository,
+    index: &mut Index,
+    message: &str,
+    parents: &[&Commit],
+) -> Commit<'a> {
+    let tree = index.write_tree_to(repo).unwrap();
     let tree = repo.find_tree(tree).unwrap();
 
     let signature = repo.signature().unwrap();
     let commit = repo
-        .commit(
-            None,
-            &signature,
-            &signature,
-            message,
-            &tree,
-            &[&head_commit(repo)],
-        )
+        .commit(None, &signature, &signature, message, &tree, parents)
         .unwrap();
 
-    let commit = oid_to_commit(&repo, commit);
+    let commit = oid_to_commit(repo, commit);
 
+    if repo.state() != RepositoryState::Clean {
+        // "Complete" the cherry-pick. There is likely a better way to do
+        // this that I haven't found so far.
+        repo.cleanup_state().unwrap();
+        repo.set_head_detached(commit.id()).unwrap();
+    }
+
+    commit
+}
+
+/// Conflicts can't be resolved in memory, so the conflicting operation is replayed in the
+/// working tree on top of `tip` (using `replay`). Wait for the user to resolve the conflicts
+/// there, and return the resolved index.
+fn resolve_conflicts<F>(
+    repo: &Repository,
+    tip: &Commit,
+    operation: &str,
+    options: RebaseOptions,
+    replay: F,
+) -> Index
+where
+    F: FnOnce(),
+{
     let mut cb = CheckoutBuilder::new();
     cb.force();
+    checkout_commit(repo, tip, Some(&mut cb));
 
-    checkout_commit(&repo, &commit, Some(&mut cb));
+    replay();
 
-    // "Complete" the cherry-pick. There is likely a better way to do
-    // this that I haven't found so far.
-    repo.cleanup_state().unwrap();
+    let mut index = repo.index().unwrap();
+    index.read(true).unwrap();
+    let conflicts = rerere::conflicts(repo, &mut index).unwrap();
 
-    commit
+    if options.rerere {
+        rerere::resolve_in_workdir(repo, &mut index, &conflicts).unwrap();
+    }
+
+    if options.mergetool {
+        for path in rerere::conflicted_paths(&index).unwrap() {
+            // `git mergetool` stages the file once the tool reports success
+            let status = Command::new("git")
+                .arg("mergetool")
+                .arg("--no-prompt")
+                .arg("--")
+                .arg(&path)
+                .current_dir(repo.workdir().unwrap())
+                .status();
+
+            if !matches!(status, Ok(status) if status.success()) {
+                println!("`git mergetool` didn't resolve {}", path.display());
+            }
+        }
+    }
+
+    // Reload index from disk (`git mergetool` stages what it resolves)
+    index.read(true).unwrap();
+
+    if index.has_conflicts() {
+        let prompt = format!("Conflicts! Resolve manually and `git add` each one (don't run any `git {}` commands, though).", operation);
+        loop_until_confirm(&prompt);
+        index.read(true).unwrap();
+    } else {
+        println!("All conflicts were resolved automatically.");
+    }
+
+    if options.rerere {
+        rerere::record(repo, &conflicts, &index).unwrap();
+    }
+
+    index
+}
+
+/// Recreate the merge commit `from` on top of `tip`. Parents that were rewritten earlier
+/// on in this run are swapped out for their rewritten counterparts.
+fn recreate_merge<'a>(
+    repo: &'a Repository,
+    from: &Commit,
+    tip: &Commit<'a>,
+    rewritten: &HashMap<Oid, Oid>,
+    options: RebaseOptions,
+) -> Commit<'a> {
+    if from.parent_count() > 2 {
+        panic!("Exiting: I don't know how to deal with octopus merges correctly.");
+    }
+
+    let other = from.parent_id(1).unwrap();
+    let other = oid_to_commit(repo, *rewritten.get(&other).unwrap_or(&other));
+
+    println!("Recreating merge: {:?}", from);
+    let mut index = repo.merge_commits(tip, &other, None).unwrap();
+
+    let conflicted = index.has_conflicts()
+        && (!options.rerere || rerere::resolve_in_index(repo, &mut index).unwrap());
+    if conflicted {
+        index = resolve_conflicts(repo, tip, "merge", options, || {
+            let mut cb = CheckoutBuilder::new();
+            cb.allow_conflicts(true);
+
+            let annotated = repo.find_annotated_commit(other.id()).unwrap();
+            repo.merge(&[&annotated], None, Some(&mut cb)).unwrap();
+        });
+    }
+
+    create_commit(repo, &mut index, from.message().unwrap(), &[tip, &other])
+}
+
+fn is_empty(commit: &Commit) -> bool {
+    match commit.parent(0) {
+        Ok(parent) => parent.tree_id() == commit.tree_id(),
+        Err(_) => false,
+    }
+}
+
+/// For a `fixup!` or `squash!` commit, whether it squashes (rather than fixes up) and the
+/// summary (or SHA prefix) of the commit it targets. `fixup! fixup! foo` targets `foo`.
+fn autosquash_target(message: &str) -> Option<(bool, &str)> {
+    let summary = message.lines().next()?;
+    let (squash, mut target) = if let Some(target) = summary.strip_prefix("fixup! ") {
+        (false, target)
+    } else if let Some(target) = summary.strip_prefix("squash! ") {
+        (true, target)
+    } else {
+        return None;
+    };
+
+    while let Some(rest) = target
+        .strip_prefix("fixup! ")
+        .or_else(|| target.strip_prefix("squash! "))
+    {
+        target = rest;
+    }
+
+    Some((squash, target.trim()))
 }
 
-fn cherry_pick_range(repo: &Repository, walk: &mut Revwalk) {
-    for from in walk {
-        let from = oid_to_commit(&repo, from.unwrap());
+/// The commit among `candidates` (earlier commits and their summaries, oldest first) that a
+/// fixup for `target` applies to, like `git rebase --autosquash`: the latest one with exactly
+/// that summary, or else whose SHA starts with `target`, or else whose summary starts with
+/// `target` (for fixups created with a shortened subject).
+fn find_autosquash_target(candidates: &[(Oid, &str)], target: &str) -> Option<Oid> {
+    let latest = |matches: &dyn Fn(Oid, &str) -> bool| {
+        candidates
+            .iter()
+            .rev()
+            .find(|(oid, summary)| matches(*oid, summary))
+            .map(|(oid, _)| *oid)
+    };
+    let is_sha = target.len() >= 4 && target.chars().all(|c| c.is_ascii_hexdigit());
+
+    latest(&|_, summary| summary == target)
+        .or_else(|| latest(&|oid, _| is_sha && oid.to_string().starts_with(target)))
+        .or_else(|| latest(&|_, summary| summary.starts_with(target)))
+}
+
+/// A commit as far as `autosquash_order` is concerned.
+struct Pick<'a> {
+    oid: Oid,
+    summary: &'a str,
+    /// Merges are never squashed into anything
+    merge: bool,
+}
+
+/// Move every `fixup!`/`squash!` commit in `commits` to just after the earlier commit it targets
+/// (and the fixups already moved there), like `git rebase --autosquash`. Returns the new order,
+/// and the target of each fixup along with whether it squashes.
+fn autosquash_order(commits: &[Pick]) -> (Vec<Oid>, HashMap<Oid, (Oid, bool)>) {
+    let mut ordered: Vec<Oid> = vec![];
+    let mut fixups = HashMap::new();
+
+    for commit in commits {
+        let target = match autosquash_target(commit.summary) {
+            Some((squash, target)) if !commit.merge => {
+                // Only commits that stay where they are can be targeted
+                let candidates = commits
+                    .iter()
+                    .filter(|c| ordered.contains(&c.oid) && !fixups.contains_key(&c.oid))
+                    .map(|c| (c.oid, c.summary))
+                    .collect::<Vec<_>>();
+                find_autosquash_target(&candidates, target).map(|target| (target, squash))
+            }
+            _ => None,
+        };
+
+        match target {
+            Some((target, squash)) => {
+                let mut position = ordered.iter().position(|o| *o == target).unwrap() + 1;
+                while position < ordered.len()
+                    && fixups.get(&ordered[position]).map(|(t, _)| *t) == Some(target)
+                {
+                    position += 1;
+                }
+
+                ordered.insert(position, commit.oid);
+                fixups.insert(commit.oid, (target, squash));
+            }
+            None => ordered.push(commit.oid),
+        }
+    }
+
+    (ordered, fixups)
+}
+
+fn autosquash(repo: &Repository, commits: Vec<Oid>) -> (Vec<Oid>, HashMap<Oid, (Oid, bool)>) {
+    let commits = commits
+        .into_iter()
+        .map(|oid| oid_to_commit(repo, oid))
+        .collect::<Vec<_>>();
+    let picks = commits
+        .iter()
+        .map(|commit| Pick {
+            oid: commit.id(),
+            summary: commit.summary().unwrap_or_default(),
+            merge: commit.parent_count() > 1,
+        })
+        .collect::<Vec<_>>();
+
+    autosquash_order(&picks)
+}
+
+/// Cherry-pick `from` onto `tip` in memory, falling back to the working tree to resolve
+/// conflicts.
+fn cherry_pick(repo: &Repository, from: &Commit, tip: &Commit, options: RebaseOptions) -> Index {
+    let mut index = repo.cherrypick_commit(from, tip, 0, None).unwrap();
+
+    let conflicted = index.has_conflicts()
+        && (!options.rerere || rerere::resolve_in_index(repo, &mut index).unwrap());
+    if conflicted {
+        index = resolve_conflicts(repo, tip, "cherry-pick", options, || {
+            let mut cb = CheckoutBuilder::new();
+            cb.allow_conflicts(true);
+            let mut opts = CherrypickOptions::new();
+            opts.checkout_builder(cb);
+
+            repo.cherrypick(from, Some(&mut opts)).unwrap();
+        });
+    }
+
+    index
+}
+
+/// Cherry-pick every commit in `walk` onto `tip` in memory, and return the new tip. With
+/// `autosquash`, fixups are squashed into their targets along the way.
+fn cherry_pick_range<'a>(
+    repo: &'a Repository,
+    walk: &mut Revwalk,
+    onto: Oid,
+    mut tip: Commit<'a>,
+    options: RebaseOptions,
+    rewritten: &mut HashMap<Oid, Oid>,
+) -> Commit<'a> {
+    let commits = walk.map(|oid| oid.unwrap()).collect::<Vec<_>>();
+    let (commits, fixups) = if options.autosquash {
+        autosquash(repo, commits)
+    } else {
+        (commits, HashMap::new())
+    };
+
+    // The commit (in the original branch) that `tip` was picked from, so fixups only squash
+    // into their own target
+    let mut picked_from = None;
+
+    for from in commits {
+        let from = oid_to_commit(repo, from);
+
+        if let Some((target, squash)) = fixups.get(&from.id()) {
+            if picked_from == Some(*target) {
+                println!("Squashing: {:?}", from);
+                let mut index = cherry_pick(repo, &from, &tip, options);
+
+                let message = match from.message().unwrap().split_once('\n') {
+                    Some((_, body)) if *squash && !body.trim().is_empty() => {
+                        format!(
+                            "{}\n{}\n",
+                            tip.message().unwrap().trim_end(),
+                            body.trim_end()
+                        )
+                    }
+                    _ => tip.message().unwrap().to_string(),
+                };
+                let parents = tip.parents().collect::<Vec<_>>();
+                let parents = parents.iter().collect::<Vec<_>>();
+
+                tip = create_commit(repo, &mut index, &message, &parents);
+                rewritten.insert(*target, tip.id());
+                rewritten.insert(from.id(), tip.id());
+                continue;
+            }
+        }
+        picked_from = None;
 
         if from.parent_count() > 1 {
-            panic!("Exiting: I don't know how to deal with merge commits correctly.");
+            match options.merges {
+                MergeStrategy::Abort => panic!("Exiting: I don't know how to deal with merge commits correctly (try `--merges drop` or `--merges rebase`)."),
+                MergeStrategy::Drop => {
+                    let in_base = from
+                        .parent_ids()
+                        .skip(1)
+                        .all(|parent| is_ancestor(repo, parent, onto));
+
+                    if !in_base {
+                        panic!("Exiting: {:?} merges in commits that aren't part of the new base; try `--merges rebase` instead.", from);
+                    }
+
+                    println!("Dropping merge: {:?}", from);
+                    rewritten.insert(from.id(), tip.id());
+                    continue;
+                }
+                MergeStrategy::Rebase => {
+                    tip = recreate_merge(repo, &from, &tip, rewritten, options);
+                    rewritten.insert(from.id(), tip.id());
+                    continue;
+                }
+            }
         }
 
+        println!("Cherry-picking: {:?}", from);
+        let mut index = cherry_pick(repo, &from, &tip, options);
+
+        // Commits that were empty to begin with are kept, but commits that only became empty
+        // (because their changes are already part of the new base) are dropped.
+        let tree = index.write_tree_to(repo).unwrap();
+        if !options.keep_empty && tree == tip.tree_id() && !is_empty(&from) {
+            println!("Dropping commit that is now empty: {:?}", from);
+            if repo.state() != RepositoryState::Clean {
+                repo.cleanup_state().unwrap();
+            }
+            rewritten.insert(from.id(), tip.id());
+            continue;
+        }
+
+        tip = create_commit(repo, &mut index, from.message().unwrap(), &[&tip]);
+        rewritten.insert(from.id(), tip.id());
+        picked_from = Some(from.id());
+    }
+
+    tip
+}
+
+/// Rebuild each `(branch, boundary)` in order: the commits reachable from `branch` but not from
+/// `boundary` are replayed on top of `onto` for the first branch, and on top of the previous
+/// branch's new tip after that. Returns the new tip of every branch; no branches are moved.
+pub fn replay_branches<'a, 'b>(
+    repo: &'a Repository,
+    onto: Commit<'a>,
+    branches: &[(&'b str, Oid)],
+    options: RebaseOptions,
+) -> Vec<(&'b str, Commit<'a>)> {
+    let mut rewritten = HashMap::new();
+    let mut tip = onto.clone();
+    let mut new_tips = vec![];
+
+    for (branch, boundary) in branches {
+        println!("\nWorking on branch: {:?}", branch);
+
+        let from = rev_to_commit(repo, branch);
+
+        let mut walk = repo.revwalk().unwrap();
+        walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE).unwrap();
+        // Only follow the branch itself; commits brought in by merges are handled by `merges`
+        walk.simplify_first_parent().unwrap();
+        walk.push(from.id()).unwrap();
+        walk.hide(*boundary).unwrap();
+
+        // TODO: Simplify by using rebase instead of cherry-pick
+        tip = cherry_pick_range(repo, &mut walk, onto.id(), tip, options, &mut rewritten);
+
+        // Record the commit (in the new stack) that the local branch should now point to.
+        // Actually perform the switch later on in a batch so we don't leave the repo in
+        // a troubled state if this process is interrupted.
+        new_tips.push((*branch, tip.clone()));
+    }
+
+    new_tips
+}
+
+/// Back up the branches in `new_tips`, push them (only if `push`) and point the local branches
+/// at their new tips. Each push is leased on the SHA in `previous` (as returned by
+/// `remote::fetch`), or else on the branch's remote-tracking branch, so a branch that has never
+/// been pushed must not exist on `remote` yet either.
+pub fn publish_branches(
+    identifier: &str,
+    repo: &Repository,
+    remote: &str,
+    new_tips: &[(&str, Commit)],
+    previous: &HashMap<String, Oid>,
+    push: bool,
+) -> Result<(), Box<dyn Error>> {
+    let leases = new_tips
+        .iter()
+        .map(|(branch, tip)| {
+            let lease = lease(repo, remote, branch, previous.get(*branch).copied());
+            (*branch, tip.id(), lease)
+        })
+        .collect::<Vec<_>>();
+
+    let branches = leases
+        .iter()
+        .map(|(branch, _, _)| *branch)
+        .collect::<Vec<_>>();
+    backup::create(repo, identifier, remote, &branches)?;
+
+    let rejected = if push {
+        confirm_and_push(repo, remote, &leases)?
+    } else {
+        for (branch, _, lease) in leases.iter() {
+            remote::record_lease(repo, remote, branch, *lease)?;
+        }
+        vec![]
+    };
+
+    println!("\nUpdating local branches so they point to the new stack.\n");
+    for (branch, target) in new_tips {
+        println!("  + Branch {} now points to {}", branch, target.id());
+        update_branch(repo, branch, target)?;
+    }
+
+    if !push {
+        println!(
+            "\nNothing was pushed; run `gh-stack push {}` when you're ready.",
+            identifier
+        );
+    }
+
+    remote::check_rejected(rejected)
+}
+
+/// The SHA a push of `branch` has to be leased on: the lease recorded when it was last rewritten
+/// without being pushed, or else the remote-tracking SHA the new tip was built on (`previous`,
+/// from before the latest fetch), or else the current remote-tracking SHA. Zero if the branch
+/// doesn't exist on `remote`.
+fn lease(repo: &Repository, remote: &str, branch: &str, previous: Option<Oid>) -> Oid {
+    remote::recorded_lease(repo, remote, branch)
+        .or(previous)
+        .unwrap_or_else(|| {
+            repo.refname_to_id(&format!("refs/remotes/{}/{}", remote, branch))
+                .unwrap_or_else(|_| Oid::zero())
+        })
+}
+
+/// Point the local branch `branch` at `target`. If `branch` is checked out, the working
+/// tree is brought along with it; only files that actually differ are touched, and local edits
+/// that would be overwritten make it fail without moving the branch.
+pub fn update_branch(repo: &Repository, branch: &str, target: &Commit) -> Result<(), git2::Error> {
+    let refname = format!("refs/heads/{}", branch);
+    let head = repo
+        .head()
+        .ok()
+        .and_then(|head| head.name().map(String::from));
+
+    if head.as_deref() == Some(&refname[..]) {
         let mut cb = CheckoutBuilder::new();
-        cb.allow_conflicts(true);
-        let mut opts = CherrypickOptions::new();
-        opts.checkout_builder(cb);
+        cb.safe();
+        repo.checkout_tree(target.as_object(), Some(&mut cb))?;
+    }
 
-        println!("Cherry-picking: {:?}", from);
-        repo.cherrypick(&from, Some(&mut opts)).unwrap();
+    repo.reference(&refname, target.id(), true, "gh-stack: restack")?;
+    Ok(())
+}
+
+/// Whether the index or working tree has changes to tracked files that haven't been committed.
+pub fn is_dirty(repo: &Repository) -> bool {
+    let mut options = StatusOptions::new();
+    options.include_untracked(false).exclude_submodules(true);
+
+    let statuses = repo.statuses(Some(&mut options)).unwrap();
+    statuses
+        .iter()
+        .any(|entry| !entry.status().is_empty() && !entry.status().contains(Status::IGNORED))
+}
+
+/// Stash uncommitted changes, if there are any. Returns `true` if anything was stashed.
+pub fn stash(repo: &mut Repository) -> Result<bool, Box<dyn Error>> {
+    if !is_dirty(repo) {
+        return Ok(false);
+    }
+
+    let signature = repo.signature()?;
+    let stash = repo.stash_save(&signature, "gh-stack autostash", None)?;
+    println!("Stashed uncommitted changes in {}", stash);
+
+    Ok(true)
+}
+
+/// Restore the changes stashed by `stash`.
+pub fn unstash(repo: &mut Repository) -> Result<(), Box<dyn Error>> {
+    repo.stash_pop(0, None).map_err(|e| {
+        format!(
+            "Couldn't restore your stashed changes (they're still in `git stash list`): {}",
+            e
+        )
+    })?;
+    println!("Restored stashed changes");
+
+    Ok(())
+}
+
+/// Point out local branches that are missing commits from their remote-tracking branch. The
+/// stack is rebuilt from the local branches, so commits that only exist on the remote (pushed by
+/// someone else, say) are left out. A branch that has diverged has usually just been rewritten
+/// locally, but one that is only behind almost certainly hasn't been updated yet, so that needs
+/// confirming.
+fn warn_if_diverged(repo: &Repository, remote: &str, branches: &[&str]) {
+    for branch in branches {
+        let local = repo.refname_to_id(&format!("refs/heads/{}", branch));
+        let tracking = repo.refname_to_id(&format!("refs/remotes/{}/{}", remote, branch));
+
+        if let (Ok(local), Ok(tracking)) = (local, tracking) {
+            let (ahead, behind) = repo.graph_ahead_behind(local, tracking).unwrap();
+
+            if ahead > 0 && behind > 0 {
+                println!(
+                    "{}: {} and {} have diverged ({} and {} different commits respectively); using {}",
+                    style("Warning").yellow(),
+                    branch,
+                    remote_ref(remote, branch),
+                    ahead,
+                    behind,
+                    branch
+                );
+            } else if behind > 0 {
+                let prompt = format!(
+                    "{}: {} is {} commit(s) behind {}, and those commits would be dropped from the stack.",
+                    style("Warning").yellow(),
+                    branch,
+                    behind,
+                    remote_ref(remote, branch)
+                );
+                loop_until_confirm(&prompt);
+            }
+        }
+    }
+}
+
+/// Remembers what `HEAD` pointed at, and points it back there (re-attaching it to the original
+/// branch, wherever that branch ends up) when dropped. Resolving conflicts leaves `HEAD`
+/// detached, so this runs on every exit from a rebase, including failures and panics (but not
+/// when the process is killed, e.g. by Ctrl-C at a prompt).
+pub struct HeadGuard<'a> {
+    repo: &'a Repository,
+    branch: Option<String>,
+    detached_at: Option<Oid>,
+}
+
+impl<'a> HeadGuard<'a> {
+    pub fn new(repo: &'a Repository) -> HeadGuard<'a> {
+        let head = repo.head().ok();
+        let branch = head
+            .as_ref()
+            .filter(|head| head.is_branch())
+            .and_then(|head| head.name().map(String::from));
+        let detached_at = head
+            .filter(|head| !head.is_branch())
+            .and_then(|head| head.target());
+
+        HeadGuard {
+            repo,
+            branch,
+            detached_at,
+        }
+    }
+
+    fn restore(&self) -> Result<(), git2::Error> {
+        let repo = self.repo;
+        let current = repo.head().ok();
 
-        let mut index = repo.index().unwrap();
+        let unchanged = match (&self.branch, self.detached_at) {
+            (Some(branch), _) => {
+                current.and_then(|head| head.name().map(String::from)) == Some(branch.clone())
+            }
+            (None, Some(oid)) => {
+                repo.head_detached()? && current.and_then(|head| head.target()) == Some(oid)
+            }
+            (None, None) => true,
+        };
+
+        if repo.state() == RepositoryState::Clean && unchanged {
+            return Ok(());
+        }
+
+        repo.cleanup_state()?;
+
+        match (&self.branch, self.detached_at) {
+            (Some(branch), _) => repo.set_head(branch)?,
+            (None, Some(oid)) => repo.set_head_detached(oid)?,
+            (None, None) => return Ok(()),
+        }
+
+        let mut cb = CheckoutBuilder::new();
+        cb.force();
+        repo.checkout_head(Some(&mut cb))?;
+
+        match &self.branch {
+            Some(branch) => println!("Checked out {} again", branch),
+            None => println!("Detached HEAD at {:?} again", self.detached_at),
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> Drop for HeadGuard<'a> {
+    fn drop(&mut self) {
+        if let Err(e) = self.restore() {
+            eprintln!("Couldn't restore HEAD: {}", e);
+        }
+    }
+}
+
+/// Show what's about to be pushed, and push it once the user confirms. Returns the branches the
+/// remote rejected.
+fn confirm_and_push(
+    repo: &Repository,
+    remote: &str,
+    leases: &[(&str, Oid, Oid)],
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let refspecs = leases
+        .iter()
+        .map(|(branch, target, _)| format!("{}:refs/heads/{}", target, branch))
+        .collect::<Vec<_>>();
+
+    println!("\n{:?}", refspecs);
+    loop_until_confirm("Going to push these refspecs ☝️ ");
+
+    Ok(remote::push_with_lease(repo, remote, leases)?)
+}
+
+/// Push every open branch in the stack whose local tip differs from its remote-tracking branch
+/// (after a `--no-push` autorebase, say). Each push is leased on the remote-tracking SHA the
+/// branch was rewritten on top of (see `lease`), so commits pushed by someone else since then
+/// are never overwritten.
+pub fn push_stack(
+    identifier: &str,
+    stack: FlatDep,
+    repo: &Repository,
+    remote: &str,
+) -> Result<(), Box<dyn Error>> {
+    let mut leases = vec![];
+
+    for (pr, _) in stack.iter() {
+        if *pr.state() != PullRequestStatus::Open {
+            continue;
+        }
+
+        let local = repo.refname_to_id(&format!("refs/heads/{}", pr.head()))?;
+        let tracking = repo
+            .refname_to_id(&format!("refs/remotes/{}/{}", remote, pr.head()))
+            .unwrap_or_else(|_| Oid::zero());
+
+        if local != tracking {
+            leases.push((pr.head(), local, lease(repo, remote, pr.head(), None)));
+        }
+    }
+
+    if leases.is_empty() {
+        println!(
+            "Every branch in the stack is already up to date on {}.",
+            remote
+        );
+        return Ok(());
+    }
+
+    let branches = leases
+        .iter()
+        .map(|(branch, _, _)| *branch)
+        .collect::<Vec<_>>();
+    backup::create(repo, identifier, remote, &branches)?;
+
+    let rejected = confirm_and_push(repo, remote, &leases)?;
+    remote::check_rejected(rejected)
+}
+
+/// Create the local branch `branch` at the tip of the local branch `parent`, and return that tip.
+pub fn create_branch_at<'a>(
+    repo: &'a Repository,
+    branch: &str,
+    parent: &str,
+) -> Result<Commit<'a>, Box<dyn Error>> {
+    let tip = repo
+        .find_branch(parent, BranchType::Local)?
+        .get()
+        .peel_to_commit()?;
+    repo.branch(branch, &tip, false)?;
+
+    println!("  + Created branch {} at {}", branch, tip.id());
+    Ok(tip)
+}
+
+/// The branch local stacks are based on, and its tip: `base` if it's passed, or else the
+/// default branch of `origin` (what `refs/remotes/origin/HEAD` points at), `main` or `master`.
+/// The local branch is used if there is one, and the remote-tracking branch otherwise.
+pub fn trunk(repo: &Repository, base: Option<&str>) -> Result<(String, Oid), Box<dyn Error>> {
+    let name = match base {
+        Some(base) => base.to_string(),
+        None => repo
+            .find_reference("refs/remotes/origin/HEAD")
+            .ok()
+            .and_then(|head| head.symbolic_target().map(String::from))
+            .and_then(|target| {
+                target
+                    .strip_prefix("refs/remotes/origin/")
+                    .map(String::from)
+            })
+            .or_else(|| {
+                ["main", "master"]
+                    .iter()
+                    .find(|name| repo.find_branch(name, BranchType::Local).is_ok())
+                    .map(|name| name.to_string())
+            })
+            .ok_or("Couldn't tell which branch the stack is based on; pass `--base`")?,
+    };
+
+    let tip = repo
+        .revparse_single(&name)
+        .or_else(|_| repo.revparse_single(&remote_ref("origin", &name)))
+        .and_then(|object| object.peel_to_commit())
+        .map_err(|_| format!("There's no branch called {}", name))?
+        .id();
+
+    Ok((name, tip))
+}
+
+/// The branch each local branch is stacked on, judging by commit ancestry: the closest other
+/// local branch whose tip is an ancestor of its tip, or else the trunk (see `trunk`), which is
+/// the end of every chain. Branches that are already part of the trunk (merged, or left behind
+/// it) aren't stacked on anything, and can't be anything's parent either. Branches that point
+/// at the same commit can't be told apart, so they're never each other's parent.
+pub fn local_parents(
+    repo: &Repository,
+    base: Option<&str>,
+) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    let (trunk, trunk_tip) = trunk(repo, base)?;
+
+    let mut tips = vec![];
+    for branch in repo.branches(Some(BranchType::Local))? {
+        let (branch, _) = branch?;
+        if let Some(name) = branch.name()? {
+            let tip = branch.get().peel_to_commit()?.id();
+            if name != trunk && !is_ancestor(repo, tip, trunk_tip) {
+                tips.push((name.to_string(), tip));
+            }
+        }
+    }
+
+    let mut parents = HashMap::new();
+    for (branch, tip) in &tips {
+        if repo.merge_base(*tip, trunk_tip).is_err() {
+            continue;
+        }
+
+        let ancestors = tips
+            .iter()
+            .filter(|(_, other)| other != tip && is_ancestor(repo, *other, *tip))
+            .collect::<Vec<_>>();
+
+        // Every other ancestor is an ancestor of the closest one too
+        let closest = ancestors.iter().find(|(_, candidate)| {
+            ancestors
+                .iter()
+                .all(|(_, other)| is_ancestor(repo, *other, *candidate))
+        });
+
+        let parent = match closest {
+            Some((parent, _)) => parent.clone(),
+            None => trunk.clone(),
+        };
+        parents.insert(branch.clone(), parent);
+    }
+
+    Ok(parents)
+}
+
+/// Local branches stacked on `branch`, directly or indirectly, parents first.
+pub fn local_descendants(parents: &HashMap<String, String>, branch: &str) -> Vec<String> {
+    let mut out = vec![];
+    let mut queue = std::collections::VecDeque::from(vec![branch.to_string()]);
+
+    while let Some(parent) = queue.pop_front() {
+        let mut children = parents
+            .iter()
+            .filter(|(_, p)| **p == parent)
+            .map(|(child, _)| child.clone())
+            .collect::<Vec<_>>();
+        children.sort();
+
+        queue.extend(children.iter().cloned());
+        out.extend(children);
+    }
+
+    out
+}
+
+/// Commit the index on top of `tip` (or amend `tip` with it) and move `HEAD` along. Returns the
+/// old and new tips.
+fn commit_staged(
+    repo: &Repository,
+    tip: &Commit,
+    message: Option<&str>,
+    amend: bool,
+) -> Result<(Oid, Oid), Box<dyn Error>> {
+    let tree = repo.find_tree(repo.index()?.write_tree()?)?;
+
+    if amend {
+        let oid = tip.amend(Some("HEAD"), None, None, None, message, Some(&tree))?;
+        println!("Amended {}: now {}", tip.id(), oid);
+        return Ok((tip.id(), oid));
+    }
+
+    if tree.id() == tip.tree_id() {
+        return Err("Nothing is staged; `git add` the changes to commit first.".into());
+    }
 
-        if index.has_conflicts() {
-            let prompt = "Conflicts! Resolve manually and `git add` each one (don't run any `git cherry-pick` commands, though).";
-            loop_until_confirm(prompt);
+    let message = match message {
+        Some(message) => message.to_string(),
+        None => dialoguer::Editor::new()
+            .edit("")?
+            .filter(|message| !message.trim().is_empty())
+            .ok_or("Aborting: the commit message is empty")?,
+    };
+
+    let signature = repo.signature()?;
+    let oid = repo.commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        &message,
+        &tree,
+        &[tip],
+    )?;
+    println!("Committed {}", oid);
+    Ok((tip.id(), oid))
+}
+
+/// Commit the staged changes on the current branch (or amend its tip with them), then rebuild
+/// every local branch stacked on it on top of the new commit, once the user has confirmed the
+/// list. Only local branches are touched; push them with `push` or `autorebase` later. They're
+/// backed up first (along with their remote-tracking branches on `remote`, or else the current
+/// branch's upstream remote), under the current branch's name.
+pub fn modify(
+    repo: &mut Repository,
+    remote: Option<&str>,
+    message: Option<&str>,
+    amend: bool,
+) -> Result<(), Box<dyn Error>> {
+    let head = repo.head()?;
+    if !head.is_branch() {
+        return Err("HEAD is detached; check out a branch first.".into());
+    }
+    let branch = head.shorthand().unwrap().to_string();
+    let remote = match remote {
+        Some(remote) => remote.to_string(),
+        None => repo
+            .branch_upstream_remote(head.name().unwrap())
+            .ok()
+            .and_then(|remote| remote.as_str().map(String::from))
+            .unwrap_or_else(|| "origin".to_string()),
+    };
 
-            // Reload index from disk
-            index = repo.index().unwrap();
-            index.read(true).unwrap();
+    // Worked out before committing, while the branches above still descend from the old tip
+    let parents = local_parents(repo, None)?;
+    let descendants = local_descendants(&parents, &branch);
+
+    // Run on a trunk branch, this would be every branch in the clone
+    if !descendants.is_empty() {
+        println!(
+            "These branches are stacked on {} and will be rebuilt on the new commit:\n",
+            branch
+        );
+        for descendant in &descendants {
+            println!("  {}", descendant);
         }
+        loop_until_confirm("\nGoing to move these branches ☝️ ");
+
+        let mut branches = vec![&branch[..]];
+        branches.extend(descendants.iter().map(|d| &d[..]));
+        backup::create(repo, &branch, &remote, &branches)?;
+        println!(
+            "Run `gh-stack undo '{}' -C {}` to put them back.\n",
+            branch,
+            repo.workdir().unwrap_or_else(|| repo.path()).display()
+        );
+    }
+
+    let (old_tip, new_tip) = commit_staged(repo, &head.peel_to_commit()?, message, amend)?;
+    drop(head);
+
+    if descendants.is_empty() {
+        return Ok(());
+    }
 
-        create_commit(&repo, &mut index, from.message().unwrap());
+    // Resolving conflicts uses the working tree, so keep anything that wasn't committed safe
+    let stashed = stash(repo)?;
+
+    let result = restack_descendants(repo, &parents, &descendants, &branch, old_tip, new_tip);
+
+    if stashed {
+        unstash(repo)?;
+    }
+
+    result
+}
+
+/// Where the commits of a branch stacked on `branch` start: the remote-tracking SHA of `branch`
+/// from before the fetch (in `previous`), like in `autorebase`, since that's what was pushed
+/// when the branches above it were last restacked. Falls back to the local tip if `branch`
+/// hasn't been pushed (or wasn't fetched).
+pub fn restack_boundary(
+    repo: &Repository,
+    previous: &HashMap<String, Oid>,
+    branch: &str,
+) -> Result<Oid, Box<dyn Error>> {
+    match previous.get(branch) {
+        Some(oid) if !oid.is_zero() => Ok(*oid),
+        _ => repo
+            .refname_to_id(&format!("refs/heads/{}", branch))
+            .map_err(|_| format!("There's no local branch for {}", branch).into()),
     }
 }
 
+/// Rebuild `branches` (`(branch, parent)` pairs, parents first) now that `base` has moved from
+/// `old_tip` to `new_tip`. Each branch is replayed onto its own parent's new tip, so branches
+/// that share a parent stay siblings; its commits start at its parent's `restack_boundary`.
+/// Returns the new tip of every branch; no branches are moved.
+pub fn restack<'a, 'b>(
+    repo: &'a Repository,
+    branches: &[(&'b str, &'b str)],
+    previous: &HashMap<String, Oid>,
+    base: &str,
+    old_tip: Oid,
+    new_tip: Commit<'a>,
+) -> Result<Vec<(&'b str, Commit<'a>)>, Box<dyn Error>> {
+    let options = RebaseOptions::default();
+
+    let mut old_tips = HashMap::new();
+    let mut new_tips = HashMap::new();
+    old_tips.insert(base, old_tip);
+    new_tips.insert(base, new_tip);
+
+    let mut out = vec![];
+    for (branch, parent) in branches {
+        old_tips.insert(*branch, restack_boundary(repo, previous, branch)?);
+
+        let onto = new_tips[parent].clone();
+        let (branch, tip) = replay_branches(repo, onto, &[(*branch, old_tips[parent])], options)
+            .pop()
+            .unwrap();
+        new_tips.insert(branch, tip.clone());
+        out.push((branch, tip));
+    }
+
+    Ok(out)
+}
+
+/// Rebuild `descendants` (parents first) now that `branch` has moved from `old_tip` to
+/// `new_tip`, and move them. Nothing has been pushed yet, so their commits start at their
+/// parents' local tips.
+fn restack_descendants(
+    repo: &Repository,
+    parents: &HashMap<String, String>,
+    descendants: &[String],
+    branch: &str,
+    old_tip: Oid,
+    new_tip: Oid,
+) -> Result<(), Box<dyn Error>> {
+    let _head = HeadGuard::new(repo);
+
+    let branches = descendants
+        .iter()
+        .map(|descendant| (&descendant[..], &parents[descendant][..]))
+        .collect::<Vec<_>>();
+    let new_tip = repo.find_commit(new_tip)?;
+    let new_tips = restack(repo, &branches, &HashMap::new(), branch, old_tip, new_tip)?;
+
+    println!("\nUpdating local branches so they point to the new stack.\n");
+    for (descendant, target) in &new_tips {
+        println!("  + Branch {} now points to {}", descendant, target.id());
+        update_branch(repo, descendant, target)?;
+    }
+
+    Ok(())
+}
+
+/// The ref a PR's head is fetched from, and the ref it's fetched into. PRs from forks don't have
+/// a branch on `remote`, so their head is fetched from GitHub's `refs/pull/<number>/head` into a
+/// temporary ref instead of a remote-tracking branch.
+fn pull_request_refspec(pr: &PullRequest, remote: &str) -> (String, String) {
+    if pr.is_from_fork() {
+        (
+            format!("refs/pull/{}/head", pr.number()),
+            format!("refs/gh-stack/checkout/{}", pr.number()),
+        )
+    } else {
+        (
+            format!("refs/heads/{}", pr.head()),
+            format!("refs/remotes/{}/{}", remote, pr.head()),
+        )
+    }
+}
+
+/// The local branch a PR is checked out into. A fork's head branch is often called something
+/// like `main` or `patch-1`, so PRs from forks get a `pr/<number>` branch instead of clobbering
+/// a local branch with the same name.
+fn local_branch_name(pr: &PullRequest) -> String {
+    if pr.is_from_fork() {
+        format!("pr/{}", pr.number())
+    } else {
+        pr.head().to_string()
+    }
+}
+
+/// Create (or fast-forward) a local branch for every open PR in the stack, so a stack someone
+/// else opened can be worked on. Branches on `remote` are tracked; PRs from forks are fetched
+/// from `refs/pull/<number>/head` into `pr/<number>` instead.
+pub fn checkout_stack(
+    stack: FlatDep,
+    repo: &Repository,
+    remote: &str,
+) -> Result<(), Box<dyn Error>> {
+    let prs = stack
+        .iter()
+        .filter(|(pr, _)| *pr.state() == PullRequestStatus::Open)
+        .map(|(pr, _)| pr)
+        .collect::<Vec<_>>();
+
+    let refspecs = prs
+        .iter()
+        .map(|pr| {
+            let (src, dst) = pull_request_refspec(pr, remote);
+            format!("+{}:{}", src, dst)
+        })
+        .collect::<Vec<_>>();
+
+    println!("Fetching {} pull requests from {}", prs.len(), remote);
+    remote::fetch_refspecs(repo, remote, &refspecs)?;
+
+    for pr in prs {
+        let (_, fetched) = pull_request_refspec(pr, remote);
+        let target = repo.find_commit(repo.refname_to_id(&fetched)?)?;
+        let name = local_branch_name(pr);
+
+        match repo.find_branch(&name, BranchType::Local) {
+            Err(_) => {
+                let mut branch = repo.branch(&name, &target, false)?;
+                if !pr.is_from_fork() {
+                    branch.set_upstream(Some(&remote_ref(remote, pr.head())))?;
+                }
+                println!("  + Created branch {} at {}", name, target.id());
+            }
+            Ok(branch) => {
+                let current = branch.get().target().unwrap();
+
+                if current == target.id() {
+                    println!("  = Branch {} is up to date", name);
+                } else if is_ancestor(repo, current, target.id()) {
+                    match update_branch(repo, &name, &target) {
+                        Ok(()) => {
+                            println!("  + Fast-forwarded branch {} to {}", name, target.id())
+                        }
+                        Err(e) => println!(
+                            "  ! Branch {} couldn't be fast-forwarded ({}); leaving it alone",
+                            name,
+                            e.message()
+                        ),
+                    }
+                } else {
+                    println!(
+                        "  ! Branch {} has local changes that aren't in #{}; leaving it alone",
+                        name,
+                        pr.number()
+                    );
+                }
+            }
+        }
+
+        if pr.is_from_fork() {
+            repo.find_reference(&fetched)?.delete()?;
+        }
+    }
+
+    Ok(())
+}
+
 pub async fn perform_rebase(
-    deps: FlatDep,
+    identifier: &str,
+    stack: FlatDep,
     repo: &Repository,
     remote: &str,
     boundary: Option<&str>,
+    onto: Option<&str>,
+    options: RebaseOptions,
 ) -> Result<(), Box<dyn Error>> {
-    let deps = deps
+    if is_dirty(repo) {
+        return Err(
+            "You have uncommitted changes; commit or stash them first (or pass `--autostash`)."
+                .into(),
+        );
+    }
+
+    let _head = HeadGuard::new(repo);
+
+    let deps = stack
         .iter()
         .filter(|(dep, _)| *dep.state() == PullRequestStatus::Open)
         .collect::<Vec<_>>();
 
     let (pr, _) = deps[0];
 
-    let base = rev_to_commit(&repo, &remote_ref(remote, pr.base()));
-    let head = rev_to_commit(&repo, pr.head());
+    for (pr, _) in deps.iter() {
+        if pr.is_from_fork() {
+            return Err(format!(
+                "#{} was opened from a fork, so there's no branch on {} to push it to; autorebase only works on stacks whose branches all live on {}.",
+                pr.number(),
+                remote,
+                remote
+            )
+            .into());
+        }
 
-    let mut stop_cherry_pick_at = match boundary {
-        Some(rev) => rev_to_commit(&repo, rev).id(),
-        None => repo.merge_base(base.id(), head.id()).unwrap(),
+        if repo.find_branch(pr.head(), BranchType::Local).is_err() {
+            return Err(format!(
+                "There's no local branch for {} (#{}); run `gh-stack checkout {}` first.",
+                pr.head(),
+                pr.number(),
+                identifier
+            )
+            .into());
+        }
+    }
+
+    // Plan against what the remote looks like right now, not whenever it was last fetched
+    let heads = deps.iter().map(|(pr, _)| pr.head()).collect::<Vec<_>>();
+    let mut branches = heads.clone();
+    branches.push(pr.base());
+    branches.extend(onto);
+    let previous = remote::fetch(repo, remote, &branches)?;
+    warn_if_diverged(repo, remote, &heads);
+
+    // The bottom PR's commits are worked out against its current base, even when the stack
+    // is moving onto a different one
+    let old_base = rev_to_commit(repo, &remote_ref(remote, pr.base()));
+    let base = rev_to_commit(repo, &remote_ref(remote, onto.unwrap_or_else(|| pr.base())));
+    let head = rev_to_commit(repo, pr.head());
+
+    let stop_cherry_pick_at = match boundary {
+        Some(rev) => rev_to_commit(repo, rev).id(),
+        None => initial_boundary(repo, &stack, &old_base, &head),
     };
-    let mut update_local_branches_to = vec![];
+    println!(
+        "Stopping the initial cherry-pick at {}",
+        stop_cherry_pick_at
+    );
+    // Every branch after the first starts where its parent's remote branch ends
+    let boundaries = std::iter::once(stop_cherry_pick_at).chain(
+        deps.iter()
+            .map(|(pr, _)| rev_to_commit(repo, &remote_ref(remote, pr.head())).id()),
+    );
+    let branches = deps
+        .iter()
+        .map(|(pr, _)| pr.head())
+        .zip(boundaries)
+        .collect::<Vec<_>>();
 
-    println!("Checking out {:?}", base);
-    checkout_commit(&repo, &base, None);
+    println!("Rebuilding the stack on {:?}", base);
+    let new_tips = replay_branches(repo, base, &branches, options);
 
-    let mut push_refspecs = vec![];
+    publish_branches(identifier, repo, remote, &new_tips, &previous, options.push)
+}
 
-    for (pr, _) in deps {
-        println!("\nWorking on PR: {:?}", pr.head());
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph;
+    use std::rc::Rc;
 
-        let from = rev_to_commit(&repo, pr.head());
+    fn pull_request(number: usize, head: &str, base: &str, merged: bool) -> Rc<PullRequest> {
+        let pr = serde_json::json!({
+            "id": number,
+            "number": number,
+            "head": { "label": head, "ref": head, "sha": format!("{:040}", number), "repo": null },
+            "base": { "label": base, "ref": base, "sha": "", "repo": null },
+            "title": format!("[stack] #{}", number),
+            "url": "",
+            "body": null,
+            "state": if merged { "closed" } else { "open" },
+            "merged_at": if merged { Some("2020-01-01T00:00:00Z") } else { None },
+            "merge_commit_sha": null,
+            "draft": false,
+        });
+        Rc::new(serde_json::from_value(pr).unwrap())
+    }
 
-        let mut walk = repo.revwalk().unwrap();
-        walk.set_sorting(Sort::TOPOLOGICAL).unwrap();
-        walk.set_sorting(Sort::REVERSE).unwrap();
-        walk.push(from.id()).unwrap();
-        walk.hide(stop_cherry_pick_at).unwrap();
+    fn stack(prs: &[Rc<PullRequest>]) -> FlatDep {
+        graph::log(&graph::build(prs))
+    }
 
-        // TODO: Simplify by using rebase instead of cherry-pick
-        // TODO: Skip if remote/<branch> is the same SHA as <branch> (only until the first cherry-pick)
-        cherry_pick_range(&repo, &mut walk);
+    /// Every `$NEW_<n>` the script uses is exported on an earlier line.
+    fn assert_parents_rebuilt_first(script: &str) {
+        let mut defined = HashSet::new();
+        for line in script.lines() {
+            for (i, _) in line.match_indices("\"$NEW_") {
+                let number = line[i + 6..].split('"').next().unwrap();
+                assert!(
+                    defined.contains(number),
+                    "$NEW_{} is used before it's defined:\n{}",
+                    number,
+                    script
+                );
+            }
+            if let Some(rest) = line.strip_prefix("export NEW_") {
+                defined.insert(rest.split('=').next().unwrap().to_string());
+            }
+        }
+    }
 
-        // Record the commit (in the new stack) that the local branch should now point to.
-        // Actually perform the switch later on in a batch so we don't leave the repo in
-        // a troubled state if this process is interrupted.
-        update_local_branches_to.push((pr.head(), head_commit(&repo)));
+    fn oid(n: u8) -> Oid {
+        Oid::from_bytes(&[n; 20]).unwrap()
+    }
 
-        // Use remote branch as boundary for the next cherry-pick
-        let from = rev_to_commit(&repo, &remote_ref(remote, pr.head()));
-        stop_cherry_pick_at = from.id();
+    fn pick(n: u8, summary: &str) -> Pick<'_> {
+        Pick {
+            oid: oid(n),
+            summary,
+            merge: false,
+        }
+    }
 
-        push_refspecs.push(format!(
-            "{}:refs/heads/{}",
-            head_commit(&repo).id(),
-            pr.head()
-        ));
+    #[test]
+    fn autosquash_target_parses_fixups_and_squashes() {
+        assert_eq!(autosquash_target("Add a thing"), None);
+        assert_eq!(
+            autosquash_target("fixup! Add a thing"),
+            Some((false, "Add a thing"))
+        );
+        assert_eq!(
+            autosquash_target("squash! Add a thing"),
+            Some((true, "Add a thing"))
+        );
+        assert_eq!(autosquash_target("fixup!Add a thing"), None);
+        assert_eq!(autosquash_target("amend! Add a thing"), None);
     }
 
-    let repo_dir = repo.workdir().unwrap().to_str().unwrap();
+    #[test]
+    fn autosquash_target_only_looks_at_the_summary() {
+        assert_eq!(
+            autosquash_target("squash! Add a thing  \n\nfixup! Something else\n"),
+            Some((true, "Add a thing"))
+        );
+        assert_eq!(
+            autosquash_target("Add a thing\n\nfixup! Something else"),
+            None
+        );
+    }
 
-    // `libgit2` doesn't support refspecs containing raw SHAs, so we shell out
-    // to `git push` instead. https://github.com/libgit2/libgit2/issues/1125
-    let mut command = Command::new("git");
-    command.arg("push").arg("-f").arg(remote);
-    command.args(push_refspecs.as_slice());
-    command.current_dir(repo_dir);
+    #[test]
+    fn autosquash_target_unwraps_chains() {
+        assert_eq!(
+            autosquash_target("fixup! fixup! Add a thing"),
+            Some((false, "Add a thing"))
+        );
+        assert_eq!(
+            autosquash_target("squash! fixup! Add a thing"),
+            Some((true, "Add a thing"))
+        );
+        assert_eq!(
+            autosquash_target("fixup! squash! Add a thing"),
+            Some((false, "Add a thing"))
+        );
+    }
 
-    println!("\n{:?}", push_refspecs);
-    loop_until_confirm("Going to push these refspecs ☝️ ");
+    #[test]
+    fn find_autosquash_target_prefers_exact_summaries() {
+        let candidates = [
+            (oid(1), "Add a thing and more"),
+            (oid(2), "Add a thing"),
+            (oid(3), "Other"),
+        ];
+        assert_eq!(
+            find_autosquash_target(&candidates, "Add a thing"),
+            Some(oid(2))
+        );
 
-    command.spawn()?.await?;
+        let candidates = [(oid(1), "Add a thing"), (oid(2), "Add a thing")];
+        assert_eq!(
+            find_autosquash_target(&candidates, "Add a thing"),
+            Some(oid(2))
+        );
+    }
 
-    println!("\nUpdating local branches so they point to the new stack.\n");
-    for (branch, target) in update_local_branches_to {
-        println!("  + Branch {} now points to {}", branch, target.id());
-        repo.branch(branch, &target, true).unwrap();
+    #[test]
+    fn find_autosquash_target_matches_sha_prefixes() {
+        let candidates = [(oid(0xab), "Add a thing"), (oid(0xcd), "Other")];
+        assert_eq!(find_autosquash_target(&candidates, "abab"), Some(oid(0xab)));
+        assert_eq!(
+            find_autosquash_target(&candidates, "abababab"),
+            Some(oid(0xab))
+        );
+        // Too short to be a SHA
+        assert_eq!(find_autosquash_target(&candidates, "aba"), None);
     }
 
-    Ok(())
+    #[test]
+    fn find_autosquash_target_matches_shortened_subjects() {
+        let candidates = [(oid(1), "Add a thing to the widget"), (oid(2), "Other")];
+        assert_eq!(
+            find_autosquash_target(&candidates, "Add a thing"),
+            Some(oid(1))
+        );
+        assert_eq!(find_autosquash_target(&candidates, "Remove a thing"), None);
+    }
+
+    #[test]
+    fn autosquash_order_moves_fixups_after_their_targets() {
+        let commits = [
+            pick(1, "First"),
+            pick(2, "Second"),
+            pick(3, "fixup! First"),
+            pick(4, "squash! Second"),
+        ];
+        let (order, fixups) = autosquash_order(&commits);
+
+        assert_eq!(order, vec![oid(1), oid(3), oid(2), oid(4)]);
+        assert_eq!(fixups[&oid(3)], (oid(1), false));
+        assert_eq!(fixups[&oid(4)], (oid(2), true));
+        assert_eq!(fixups.len(), 2);
+    }
+
+    #[test]
+    fn autosquash_order_keeps_several_fixups_in_order() {
+        let commits = [
+            pick(1, "First"),
+            pick(2, "Second"),
+            pick(3, "fixup! First"),
+            pick(4, "squash! First"),
+            pick(5, "fixup! fixup! First"),
+        ];
+        let (order, fixups) = autosquash_order(&commits);
+
+        assert_eq!(order, vec![oid(1), oid(3), oid(4), oid(5), oid(2)]);
+        assert!([3, 4, 5].iter().all(|n| fixups[&oid(*n)].0 == oid(1)));
+    }
+
+    #[test]
+    fn autosquash_order_leaves_unmatched_fixups_alone() {
+        // A fixup can only target an earlier commit, and merges aren't squashed
+        let mut merge = pick(4, "fixup! Second");
+        merge.merge = true;
+        let commits = [
+            pick(1, "fixup! Second"),
+            pick(2, "Second"),
+            pick(3, "fixup! Missing"),
+            merge,
+        ];
+        let (order, fixups) = autosquash_order(&commits);
+
+        assert_eq!(order, vec![oid(1), oid(2), oid(3), oid(4)]);
+        assert!(fixups.is_empty());
+    }
+
+    #[test]
+    fn autosquash_order_matches_shortened_subjects_and_shas() {
+        let sha = oid(0xab).to_string();
+        let by_sha = format!("fixup! {}", &sha[..8]);
+        let commits = [
+            pick(0xab, "Add a thing to the widget"),
+            pick(2, "Other"),
+            pick(3, "fixup! Add a thing"),
+            pick(4, &by_sha),
+        ];
+        let (order, _) = autosquash_order(&commits);
+
+        assert_eq!(order, vec![oid(0xab), oid(3), oid(4), oid(2)]);
+    }
+
+    #[test]
+    fn rebase_script_rebuilds_a_chain_in_order() {
+        let prs = [
+            pull_request(1, "one", "main", false),
+            pull_request(2, "two", "one", false),
+            pull_request(3, "three", "two", false),
+        ];
+        let script = generate_rebase_script(stack(&prs), "upstream", None, None);
+
+        assert_parents_rebuilt_first(&script);
+        assert!(script.contains("git fetch upstream\n"));
+        assert!(script.contains("export OLD_1=\"$(git rev-parse --verify upstream/one)\"\n"));
+        assert!(script.contains("git checkout --detach \"upstream/main\"\n"));
+        assert!(script.contains("git checkout --detach \"$NEW_1\"\n"));
+        assert!(script.contains("git cherry-pick \"$OLD_1\"..\"$OLD_2\""));
+        assert!(script.contains("git cherry-pick \"$OLD_2\"..\"$OLD_3\""));
+        assert!(script.contains("git push -f upstream HEAD:refs/heads/three\n"));
+    }
+
+    #[test]
+    fn rebase_script_rebuilds_branched_stacks_onto_their_own_parents() {
+        let prs = [
+            pull_request(1, "one", "main", false),
+            pull_request(2, "two", "one", false),
+            pull_request(3, "three", "one", false),
+            pull_request(4, "four", "three", false),
+        ];
+        let script = generate_rebase_script(stack(&prs), "origin", Some("develop"), None);
+
+        assert_parents_rebuilt_first(&script);
+        assert!(script.contains("git checkout --detach \"origin/develop\"\n"));
+        assert!(script.contains("git cherry-pick \"$OLD_1\"..\"$OLD_2\""));
+        assert!(script.contains("git cherry-pick \"$OLD_1\"..\"$OLD_3\""));
+        assert!(script.contains("git cherry-pick \"$OLD_3\"..\"$OLD_4\""));
+        assert!(!script.contains("\"$OLD_2\"..\"$OLD_3\""));
+    }
+
+    #[test]
+    fn rebase_script_skips_empty_ranges() {
+        let prs = [
+            pull_request(1, "one", "main", false),
+            pull_request(2, "two", "one", false),
+        ];
+        let script = generate_rebase_script(stack(&prs), "origin", None, None);
+
+        assert!(script.contains(
+            "if [ -n \"$(git rev-list \"$OLD_1\"..\"$OLD_2\")\" ]; then git cherry-pick \"$OLD_1\"..\"$OLD_2\"; fi\n"
+        ));
+        assert!(!script
+            .lines()
+            .any(|line| line.starts_with("git cherry-pick")));
+    }
+
+    #[test]
+    fn rebase_script_starts_after_a_merged_parent() {
+        let prs = [
+            pull_request(1, "one", "main", true),
+            pull_request(2, "two", "one", false),
+        ];
+        let script = generate_rebase_script(stack(&prs), "origin", Some("main"), None);
+
+        assert_parents_rebuilt_first(&script);
+        assert!(!script.contains("OLD_1"));
+        assert!(script.contains(&format!("export PREBASE_2=\"{:040}\"\n", 1)));
+        assert!(script.contains("git checkout --detach \"origin/main\"\n"));
+        assert!(script.contains("git cherry-pick \"$PREBASE_2\"..\"$OLD_2\""));
+    }
 }
@@ -1,8 +1,16 @@
+pub mod absorb;
 pub mod api;
+pub mod backup;
+pub mod conflicts;
+pub mod edit;
+pub mod exec;
 pub mod git;
 pub mod graph;
 pub mod markdown;
+pub mod nav;
 pub mod persist;
+pub mod remote;
+pub mod rerere;
 pub mod util;
 
 pub struct Credentials {
@@ -5,11 +5,14 @@ use std::env;
 use std::error::Error;
 use std::rc::Rc;
 
-use gh_stack::api::PullRequest;
+use gh_stack::api::{PullRequest, PullRequestStatus};
+use gh_stack::exec::ExecOptions;
+use gh_stack::git::{MergeStrategy, RebaseOptions};
 use gh_stack::graph::FlatDep;
+use gh_stack::nav::Direction;
 use gh_stack::util::loop_until_confirm;
 use gh_stack::Credentials;
-use gh_stack::{api, git, graph, markdown, persist};
+use gh_stack::{absorb, api, backup, conflicts, edit, exec, git, graph, markdown, nav, persist};
 
 fn clap<'a, 'b>() -> App<'a, 'b> {
     let identifier = Arg::with_name("identifier")
@@ -17,6 +20,11 @@ fn clap<'a, 'b>() -> App<'a, 'b> {
         .required(true)
         .help("All pull requests containing this identifier in their title form a stack");
 
+    let base = Arg::with_name("base")
+        .long("base")
+        .value_name("BRANCH")
+        .help("The branch local stacks are based on (default: the remote's default branch, `main` or `master`)");
+
     let exclude = Arg::with_name("exclude")
         .long("excl")
         .short("e")
@@ -57,7 +65,36 @@ fn clap<'a, 'b>() -> App<'a, 'b> {
                 .long("initial-cherry-pick-boundary")
                 .short("b")
                 .value_name("SHA")
-                .help("Stop the initial cherry-pick at this SHA (exclusive)"))
+                .help("Stop the initial cherry-pick at this SHA (exclusive). Detected automatically when not passed"))
+        .arg(Arg::with_name("onto")
+                .long("onto")
+                .short("o")
+                .value_name("BRANCH")
+                .help("Rebuild the stack on this branch (on the remote) instead of the bottom PR's base, and retarget the bottom PR at it"))
+        .arg(Arg::with_name("merges")
+                .long("merges")
+                .short("m")
+                .value_name("STRATEGY")
+                .possible_values(&["abort", "drop", "rebase"])
+                .help("What to do with merge commits in a branch: `drop` merges that are already part of the new base, or `rebase` them onto the rewritten parents (default: `abort`)"))
+        .arg(Arg::with_name("autostash")
+                .long("autostash")
+                .help("Stash uncommitted changes before rebuilding the stack, and restore them afterwards"))
+        .arg(Arg::with_name("no-push")
+                .long("no-push")
+                .help("Only update local branches; push them later with the `push` subcommand"))
+        .arg(Arg::with_name("autosquash")
+                .long("autosquash")
+                .help("Squash `fixup!` and `squash!` commits into the commits they target (in the same branch), like `git rebase --autosquash`"))
+        .arg(Arg::with_name("mergetool")
+                .long("mergetool")
+                .help("Launch `git mergetool` for every conflicted path when a cherry-pick conflicts"))
+        .arg(Arg::with_name("rerere")
+                .long("rerere")
+                .help("Record how conflicts are resolved, and reuse the same resolution when the same conflict comes up again"))
+        .arg(Arg::with_name("keep-empty")
+                .long("keep-empty")
+                .help("Keep commits that become empty because their changes are already part of the new base"))
         .setting(AppSettings::ArgRequiredElseHelp)
         .arg(exclude.clone())
         .arg(identifier.clone());
@@ -67,9 +104,377 @@ fn clap<'a, 'b>() -> App<'a, 'b> {
             "Print a bash script to STDOUT that can rebase/update the stack (with a little help)",
         )
         .setting(AppSettings::ArgRequiredElseHelp)
+        .arg(
+            Arg::with_name("remote")
+                .long("remote")
+                .short("r")
+                .value_name("REMOTE")
+                .help("Name of the remote the script fetches from and (force-)pushes to (default: `origin`)"),
+        )
+        .arg(
+            Arg::with_name("trunk")
+                .long("trunk")
+                .short("t")
+                .value_name("BRANCH")
+                .help("Rebuild the bottom of the stack on this branch (default: the bottom PR's base)"),
+        )
+        .arg(
+            Arg::with_name("repo")
+                .long("repo")
+                .short("C")
+                .value_name("PATH_TO_REPO")
+                .help("Path to a local copy of the repository, used to work out where the initial cherry-pick stops"),
+        )
+        .arg(exclude.clone())
+        .arg(identifier.clone());
+
+    let push = SubCommand::with_name("push")
+        .about("Push every branch in a stack whose local tip differs from the remote")
+        .setting(AppSettings::ArgRequiredElseHelp)
+        .arg(
+            Arg::with_name("remote")
+                .long("remote")
+                .short("r")
+                .value_name("REMOTE")
+                .help("Name of the remote to (force-)push the stack to (default: `origin`)"),
+        )
+        .arg(
+            Arg::with_name("repo")
+                .long("repo")
+                .short("C")
+                .value_name("PATH_TO_REPO")
+                .help("Path to a local copy of the repository"),
+        )
+        .arg(exclude.clone())
+        .arg(identifier.clone());
+
+    let checkout = SubCommand::with_name("checkout")
+        .about("Create (or fast-forward) a local branch for every pull request in a stack")
+        .setting(AppSettings::ArgRequiredElseHelp)
+        .arg(
+            Arg::with_name("remote")
+                .long("remote")
+                .short("r")
+                .value_name("REMOTE")
+                .help("Name of the remote to fetch the stack from (default: `origin`)"),
+        )
+        .arg(
+            Arg::with_name("repo")
+                .long("repo")
+                .short("C")
+                .value_name("PATH_TO_REPO")
+                .help("Path to a local copy of the repository"),
+        )
+        .arg(exclude.clone())
+        .arg(identifier.clone());
+
+    let insert = SubCommand::with_name("insert")
+        .about("Add a new branch to a stack, between an existing branch and the PR that merges into it")
+        .setting(AppSettings::ArgRequiredElseHelp)
+        .arg(
+            Arg::with_name("after")
+                .long("after")
+                .short("a")
+                .value_name("BRANCH")
+                .required(true)
+                .help("The branch the new branch is based on; the PR that merges into it is retargeted at the new branch"),
+        )
+        .arg(
+            Arg::with_name("open-pr")
+                .long("open-pr")
+                .short("o")
+                .value_name("TITLE")
+                .help("Also open a PR for the new branch, with this title (prefixed with the identifier unless it already contains it)"),
+        )
+        .arg(
+            Arg::with_name("remote")
+                .long("remote")
+                .short("r")
+                .value_name("REMOTE")
+                .help("Name of the remote to push the new branch to (default: `origin`)"),
+        )
+        .arg(
+            Arg::with_name("repo")
+                .long("repo")
+                .short("C")
+                .value_name("PATH_TO_REPO")
+                .help("Path to a local copy of the repository"),
+        )
+        .arg(exclude.clone())
+        .arg(identifier.clone())
+        .arg(
+            Arg::with_name("branch")
+                .index(2)
+                .required(true)
+                .help("Name of the new branch"),
+        );
+
+    let reorder = SubCommand::with_name("reorder")
+        .about("Change the order of the PRs in a stack in $EDITOR, then rebuild, push and re-annotate the stack")
+        .setting(AppSettings::ArgRequiredElseHelp)
+        .arg(
+            Arg::with_name("remote")
+                .long("remote")
+                .short("r")
+                .value_name("REMOTE")
+                .help("Name of the remote to (force-)push the reordered stack to (default: `origin`)"),
+        )
+        .arg(
+            Arg::with_name("repo")
+                .long("repo")
+                .short("C")
+                .value_name("PATH_TO_REPO")
+                .help("Path to a local copy of the repository"),
+        )
+        .arg(
+            Arg::with_name("prelude")
+                .long("prelude")
+                .short("p")
+                .value_name("FILE")
+                .help("Prepend the new annotation with the contents of this file"),
+        )
+        .arg(exclude.clone())
+        .arg(identifier.clone());
+
+    let fold = SubCommand::with_name("fold")
+        .about("Fold a branch into the branch it's based on, and close its PR")
+        .setting(AppSettings::ArgRequiredElseHelp)
+        .arg(
+            Arg::with_name("remote")
+                .long("remote")
+                .short("r")
+                .value_name("REMOTE")
+                .help(
+                    "Name of the remote to (force-)push the updated stack to (default: `origin`)",
+                ),
+        )
+        .arg(
+            Arg::with_name("repo")
+                .long("repo")
+                .short("C")
+                .value_name("PATH_TO_REPO")
+                .help("Path to a local copy of the repository"),
+        )
+        .arg(exclude.clone())
+        .arg(identifier.clone())
+        .arg(
+            Arg::with_name("branch")
+                .index(2)
+                .required(true)
+                .help("The branch to fold into its parent"),
+        );
+
+    let split = SubCommand::with_name("split")
+        .about("Split a branch in two: a new branch (with its own PR) for the lower commits, with the original branch stacked on top")
+        .setting(AppSettings::ArgRequiredElseHelp)
+        .arg(
+            Arg::with_name("at")
+                .long("at")
+                .short("a")
+                .value_name("SHA")
+                .help("The last commit that goes into the new branch. Picked interactively when not passed"),
+        )
+        .arg(
+            Arg::with_name("name")
+                .long("name")
+                .short("n")
+                .value_name("BRANCH")
+                .help("Name of the new branch (default: `<branch>-lower`)"),
+        )
+        .arg(
+            Arg::with_name("title")
+                .long("title")
+                .short("t")
+                .value_name("TITLE")
+                .help("Title of the new PR, prefixed with the identifier unless it already contains it (default: the summary of the last commit in the new branch)"),
+        )
+        .arg(
+            Arg::with_name("remote")
+                .long("remote")
+                .short("r")
+                .value_name("REMOTE")
+                .help("Name of the remote to push the new branch to (default: `origin`)"),
+        )
+        .arg(
+            Arg::with_name("repo")
+                .long("repo")
+                .short("C")
+                .value_name("PATH_TO_REPO")
+                .help("Path to a local copy of the repository"),
+        )
         .arg(exclude.clone())
+        .arg(identifier.clone())
+        .arg(
+            Arg::with_name("branch")
+                .index(2)
+                .required(true)
+                .help("The branch to split"),
+        );
+
+    let undo = SubCommand::with_name("undo")
+        .about("Restore the branches in a stack to where they were before an `autorebase`")
+        .setting(AppSettings::ArgRequiredElseHelp)
+        .arg(
+            Arg::with_name("repo")
+                .long("repo")
+                .short("C")
+                .value_name("PATH_TO_REPO")
+                .help("Path to a local copy of the repository"),
+        )
+        .arg(
+            Arg::with_name("backup")
+                .long("backup")
+                .short("b")
+                .value_name("TIMESTAMP")
+                .help("Restore this backup instead of the latest one"),
+        )
+        .arg(
+            Arg::with_name("list")
+                .long("list")
+                .short("l")
+                .help("List the available backups instead of restoring one"),
+        )
+        .arg(
+            Arg::with_name("push")
+                .long("push")
+                .short("p")
+                .help("Also force-push the remote branches back to where they were"),
+        )
         .arg(identifier.clone());
 
+    let exec = SubCommand::with_name("exec")
+        .about("Run a command on every branch in a stack, in order, and summarize which ones it failed on")
+        .setting(AppSettings::ArgRequiredElseHelp)
+        .arg(
+            Arg::with_name("repo")
+                .long("repo")
+                .short("C")
+                .value_name("PATH_TO_REPO")
+                .help("Path to a local copy of the repository"),
+        )
+        .arg(
+            Arg::with_name("fail-fast")
+                .long("fail-fast")
+                .short("f")
+                .help("Stop at the first branch the command fails on"),
+        )
+        .arg(
+            Arg::with_name("worktrees")
+                .long("worktrees")
+                .short("w")
+                .help("Check every branch out into its own worktree, and run the command on all of them in parallel"),
+        )
+        .arg(exclude.clone())
+        .arg(identifier.clone())
+        .arg(
+            Arg::with_name("command")
+                .index(2)
+                .required(true)
+                .multiple(true)
+                .last(true)
+                .help("The command to run, after `--`"),
+        );
+
+    let check_conflicts = SubCommand::with_name("check-conflicts")
+        .about("Predict which branches (and files) in a stack would conflict if it were rebuilt, without changing anything")
+        .setting(AppSettings::ArgRequiredElseHelp)
+        .arg(
+            Arg::with_name("remote")
+                .long("remote")
+                .short("r")
+                .value_name("REMOTE")
+                .help("Name of the remote whose (already fetched) branches the stack is rebuilt against (default: `origin`)"),
+        )
+        .arg(
+            Arg::with_name("repo")
+                .long("repo")
+                .short("C")
+                .value_name("PATH_TO_REPO")
+                .help("Path to a local copy of the repository"),
+        )
+        .arg(
+            Arg::with_name("boundary")
+                .long("initial-cherry-pick-boundary")
+                .short("b")
+                .value_name("SHA")
+                .help("Stop the initial cherry-pick at this SHA (exclusive). Detected automatically when not passed"),
+        )
+        .arg(
+            Arg::with_name("onto")
+                .long("onto")
+                .short("o")
+                .value_name("BRANCH")
+                .help("Rebuild the stack on this branch (on the remote) instead of the bottom PR's base"),
+        )
+        .arg(exclude.clone())
+        .arg(identifier.clone());
+
+    let modify = SubCommand::with_name("modify")
+        .about("Commit (or amend) staged changes on the current branch, and restack the local branches on top of it")
+        .arg(
+            Arg::with_name("message")
+                .long("message")
+                .short("m")
+                .value_name("MESSAGE")
+                .help("Commit message (default: written in $EDITOR, or the amended commit's message)"),
+        )
+        .arg(
+            Arg::with_name("amend")
+                .long("amend")
+                .short("a")
+                .help("Amend the commit at the tip of the current branch instead of adding a new one"),
+        )
+        .arg(
+            Arg::with_name("remote")
+                .long("remote")
+                .short("r")
+                .value_name("REMOTE")
+                .help("Name of the remote whose branches are backed up too (default: the current branch's upstream remote, or `origin`)"),
+        )
+        .arg(
+            Arg::with_name("repo")
+                .long("repo")
+                .short("C")
+                .value_name("PATH_TO_REPO")
+                .help("Path to a local copy of the repository (default: the current directory)"),
+        );
+
+    let absorb = SubCommand::with_name("absorb")
+        .about("Turn staged changes into fixup commits in the branches of the current stack that introduced the lines they change")
+        .arg(base.clone())
+        .arg(
+            Arg::with_name("repo")
+                .long("repo")
+                .short("C")
+                .value_name("PATH_TO_REPO")
+                .help("Path to a local copy of the repository (default: the current directory)"),
+        );
+
+    let navigation = [
+        ("up", "Check out the branch stacked on the current branch"),
+        ("down", "Check out the branch the current branch is based on"),
+        ("top", "Check out the last branch in the current stack"),
+        ("bottom", "Check out the first branch in the current stack"),
+    ]
+    .iter()
+    .map(|(name, about)| {
+        SubCommand::with_name(name)
+            .about(*about)
+            .arg(
+                Arg::with_name("repo")
+                    .long("repo")
+                    .short("C")
+                    .value_name("PATH_TO_REPO")
+                    .help("Path to a local copy of the repository (default: the current directory)"),
+            )
+            .arg(base.clone())
+            .arg(exclude.clone())
+            .arg(identifier.clone().required(false).help(
+                "Use the stack formed by the PRs containing this identifier in their title. Without it, the stack is worked out from local branches",
+            ))
+    })
+    .collect::<Vec<_>>();
+
     let app = App::new("gh-stack")
         .setting(AppSettings::SubcommandRequiredElseHelp)
         .setting(AppSettings::DisableVersion)
@@ -78,7 +483,19 @@ fn clap<'a, 'b>() -> App<'a, 'b> {
         .subcommand(annotate)
         .subcommand(log)
         .subcommand(rebase)
-        .subcommand(autorebase);
+        .subcommand(autorebase)
+        .subcommand(push)
+        .subcommand(checkout)
+        .subcommand(insert)
+        .subcommand(reorder)
+        .subcommand(fold)
+        .subcommand(split)
+        .subcommand(undo)
+        .subcommand(exec)
+        .subcommand(check_conflicts)
+        .subcommand(modify)
+        .subcommand(absorb)
+        .subcommands(navigation);
 
     app
 }
@@ -88,7 +505,7 @@ async fn build_pr_stack(
     credentials: &Credentials,
     exclude: Vec<String>,
 ) -> Result<FlatDep, Box<dyn Error>> {
-    let prs = api::search::fetch_pull_requests_matching(pattern, &credentials).await?;
+    let prs = api::search::fetch_pull_requests_matching(pattern, credentials).await?;
 
     let prs = prs
         .into_iter()
@@ -109,18 +526,22 @@ fn get_excluded(m: &ArgMatches) -> Vec<String> {
     }
 }
 
+/// Only read when a subcommand talks to GitHub, so purely local subcommands work without a token.
+fn credentials() -> Credentials {
+    let token = env::var("GHSTACK_OAUTH_TOKEN").expect("You didn't pass `GHSTACK_OAUTH_TOKEN`");
+    Credentials::new(&token)
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     dotenv::from_filename(".gh-stack").ok();
 
-    let token = env::var("GHSTACK_OAUTH_TOKEN").expect("You didn't pass `GHSTACK_OAUTH_TOKEN`");
-    let credentials = Credentials::new(&token);
     let matches = clap().get_matches();
 
     match matches.subcommand() {
         ("annotate", Some(m)) => {
             let identifier = m.value_of("identifier").unwrap();
-            let stack = build_pr_stack(identifier, &credentials, get_excluded(m)).await?;
+            let stack = build_pr_stack(identifier, &credentials(), get_excluded(m)).await?;
             let table = markdown::build_table(&stack, identifier, m.value_of("prelude"));
 
             for (pr, _) in stack.iter() {
@@ -128,14 +549,14 @@ async fn main() -> Result<(), Box<dyn Error>> {
             }
             loop_until_confirm("Going to update these PRs ☝️ ");
 
-            persist::persist(&stack, &table, &credentials).await?;
+            persist::persist(&stack, &table, &credentials()).await?;
 
             println!("Done!");
         }
 
         ("log", Some(m)) => {
             let identifier = m.value_of("identifier").unwrap();
-            let stack = build_pr_stack(identifier, &credentials, get_excluded(m)).await?;
+            let stack = build_pr_stack(identifier, &credentials(), get_excluded(m)).await?;
 
             for (pr, maybe_parent) in stack {
                 match maybe_parent {
@@ -154,15 +575,188 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
         ("rebase", Some(m)) => {
             let identifier = m.value_of("identifier").unwrap();
-            let stack = build_pr_stack(identifier, &credentials, get_excluded(m)).await?;
+            let stack = build_pr_stack(identifier, &credentials(), get_excluded(m)).await?;
+
+            let repo = match m.value_of("repo") {
+                Some(repo) => Some(Repository::open(repo)?),
+                None => None,
+            };
+            let remote = m.value_of("remote").unwrap_or("origin");
 
-            let script = git::generate_rebase_script(stack);
+            let script =
+                git::generate_rebase_script(stack, remote, m.value_of("trunk"), repo.as_ref());
             println!("{}", script);
         }
 
         ("autorebase", Some(m)) => {
             let identifier = m.value_of("identifier").unwrap();
-            let stack = build_pr_stack(identifier, &credentials, get_excluded(m)).await?;
+            let stack = build_pr_stack(identifier, &credentials(), get_excluded(m)).await?;
+
+            let repo = m
+                .value_of("repo")
+                .expect("The --repo argument is required.");
+            let mut repo = Repository::open(repo)?;
+
+            let stashed = m.is_present("autostash") && git::stash(&mut repo)?;
+
+            let remote = m.value_of("remote").unwrap_or("origin");
+            let remote = repo
+                .find_remote(remote)
+                .unwrap()
+                .name()
+                .unwrap()
+                .to_string();
+
+            let merges = match m.value_of("merges") {
+                Some("drop") => MergeStrategy::Drop,
+                Some("rebase") => MergeStrategy::Rebase,
+                _ => MergeStrategy::Abort,
+            };
+
+            let onto = m.value_of("onto");
+            let push = !m.is_present("no-push");
+            let bottom = stack
+                .iter()
+                .find(|(pr, _)| *pr.state() == PullRequestStatus::Open)
+                .map(|(pr, _)| pr.clone());
+
+            let result = git::perform_rebase(
+                identifier,
+                stack,
+                &repo,
+                &remote,
+                m.value_of("boundary"),
+                onto,
+                RebaseOptions {
+                    merges,
+                    keep_empty: m.is_present("keep-empty"),
+                    autosquash: m.is_present("autosquash"),
+                    mergetool: m.is_present("mergetool"),
+                    rerere: m.is_present("rerere"),
+                    push,
+                },
+            )
+            .await;
+
+            if stashed {
+                git::unstash(&mut repo)?;
+            }
+
+            result?;
+
+            // Retargeting the PR before its branch has been pushed would fill it with the
+            // new base's commits
+            match (onto, bottom) {
+                (Some(onto), Some(bottom)) if bottom.base() != onto && push => {
+                    api::pull_request::update_base(onto, &bottom, &credentials()).await?;
+                    println!("#{} now merges into {}", bottom.number(), onto);
+                }
+                (Some(onto), Some(bottom)) if bottom.base() != onto => {
+                    println!(
+                        "#{} still merges into {}; retarget it at {} once the stack is pushed.",
+                        bottom.number(),
+                        bottom.base(),
+                        onto
+                    );
+                }
+                _ => {}
+            }
+
+            println!("All done!");
+        }
+
+        ("push", Some(m)) => {
+            let identifier = m.value_of("identifier").unwrap();
+            let stack = build_pr_stack(identifier, &credentials(), get_excluded(m)).await?;
+
+            let repo = m
+                .value_of("repo")
+                .expect("The --repo argument is required.");
+            let repo = Repository::open(repo)?;
+
+            let remote = m.value_of("remote").unwrap_or("origin");
+            let remote = repo.find_remote(remote).unwrap();
+
+            git::push_stack(identifier, stack, &repo, remote.name().unwrap())?;
+            println!("All done!");
+        }
+
+        ("checkout", Some(m)) => {
+            let identifier = m.value_of("identifier").unwrap();
+            let stack = build_pr_stack(identifier, &credentials(), get_excluded(m)).await?;
+
+            let repo = m
+                .value_of("repo")
+                .expect("The --repo argument is required.");
+            let repo = Repository::open(repo)?;
+
+            let remote = m.value_of("remote").unwrap_or("origin");
+            let remote = repo.find_remote(remote).unwrap();
+
+            git::checkout_stack(stack, &repo, remote.name().unwrap())?;
+            println!("All done!");
+        }
+
+        ("insert", Some(m)) => {
+            let identifier = m.value_of("identifier").unwrap();
+            let stack = build_pr_stack(identifier, &credentials(), get_excluded(m)).await?;
+
+            let repo = m
+                .value_of("repo")
+                .expect("The --repo argument is required.");
+            let repo = Repository::open(repo)?;
+
+            let remote = m.value_of("remote").unwrap_or("origin");
+            let remote = repo.find_remote(remote).unwrap();
+
+            edit::insert(
+                identifier,
+                stack,
+                &repo,
+                remote.name().unwrap(),
+                m.value_of("after").unwrap(),
+                m.value_of("branch").unwrap(),
+                m.value_of("open-pr"),
+                &credentials(),
+            )
+            .await?;
+            println!("All done!");
+        }
+
+        ("reorder", Some(m)) => {
+            let identifier = m.value_of("identifier").unwrap();
+            let stack = build_pr_stack(identifier, &credentials(), get_excluded(m)).await?;
+
+            let repo = m
+                .value_of("repo")
+                .expect("The --repo argument is required.");
+            let repo = Repository::open(repo)?;
+
+            let remote = m.value_of("remote").unwrap_or("origin");
+            let remote = repo.find_remote(remote).unwrap();
+
+            let reordered = edit::reorder(
+                identifier,
+                &stack,
+                &repo,
+                remote.name().unwrap(),
+                &credentials(),
+            )
+            .await?;
+
+            if reordered {
+                // Every PR's annotation describes the old order
+                let stack = build_pr_stack(identifier, &credentials(), get_excluded(m)).await?;
+                let table = markdown::build_table(&stack, identifier, m.value_of("prelude"));
+                persist::persist(&stack, &table, &credentials()).await?;
+            }
+
+            println!("All done!");
+        }
+
+        ("fold", Some(m)) => {
+            let identifier = m.value_of("identifier").unwrap();
+            let stack = build_pr_stack(identifier, &credentials(), get_excluded(m)).await?;
 
             let repo = m
                 .value_of("repo")
@@ -172,11 +766,157 @@ async fn main() -> Result<(), Box<dyn Error>> {
             let remote = m.value_of("remote").unwrap_or("origin");
             let remote = repo.find_remote(remote).unwrap();
 
-            git::perform_rebase(stack, &repo, remote.name().unwrap(), m.value_of("boundary"))
-                .await?;
+            edit::fold(
+                identifier,
+                stack,
+                &repo,
+                remote.name().unwrap(),
+                m.value_of("branch").unwrap(),
+                &credentials(),
+            )
+            .await?;
             println!("All done!");
         }
 
+        ("split", Some(m)) => {
+            let identifier = m.value_of("identifier").unwrap();
+            let stack = build_pr_stack(identifier, &credentials(), get_excluded(m)).await?;
+
+            let repo = m
+                .value_of("repo")
+                .expect("The --repo argument is required.");
+            let repo = Repository::open(repo)?;
+
+            let remote = m.value_of("remote").unwrap_or("origin");
+            let remote = repo.find_remote(remote).unwrap();
+
+            let branch = m.value_of("branch").unwrap();
+            let lower = match m.value_of("name") {
+                Some(name) => name.to_string(),
+                None => format!("{}-lower", branch),
+            };
+
+            edit::split(
+                identifier,
+                stack,
+                &repo,
+                remote.name().unwrap(),
+                branch,
+                &lower,
+                m.value_of("at"),
+                m.value_of("title"),
+                &credentials(),
+            )
+            .await?;
+            println!("All done!");
+        }
+
+        ("undo", Some(m)) => {
+            let identifier = m.value_of("identifier").unwrap();
+
+            let repo = m
+                .value_of("repo")
+                .expect("The --repo argument is required.");
+            let repo = Repository::open(repo)?;
+
+            if m.is_present("list") {
+                for timestamp in backup::list(&repo, identifier)? {
+                    println!("{}", timestamp);
+                }
+                return Ok(());
+            }
+
+            let timestamp = match m.value_of("backup") {
+                Some(timestamp) => Some(timestamp.parse()?),
+                None => None,
+            };
+
+            backup::restore(&repo, identifier, timestamp, m.is_present("push"))?;
+            println!("All done!");
+        }
+
+        ("exec", Some(m)) => {
+            let identifier = m.value_of("identifier").unwrap();
+            let stack = build_pr_stack(identifier, &credentials(), get_excluded(m)).await?;
+
+            let repo = m
+                .value_of("repo")
+                .expect("The --repo argument is required.");
+            let repo = Repository::open(repo)?;
+
+            let command = m.values_of("command").unwrap().collect::<Vec<_>>();
+            let options = ExecOptions {
+                fail_fast: m.is_present("fail-fast"),
+                worktrees: m.is_present("worktrees"),
+            };
+
+            exec::exec(stack, &repo, &command, options)?;
+            println!("All done!");
+        }
+
+        ("check-conflicts", Some(m)) => {
+            let identifier = m.value_of("identifier").unwrap();
+            let stack = build_pr_stack(identifier, &credentials(), get_excluded(m)).await?;
+
+            let repo = m
+                .value_of("repo")
+                .expect("The --repo argument is required.");
+            let repo = Repository::open(repo)?;
+            let remote = m.value_of("remote").unwrap_or("origin");
+
+            conflicts::check_conflicts(
+                stack,
+                &repo,
+                remote,
+                m.value_of("boundary"),
+                m.value_of("onto"),
+            )?;
+            println!("No conflicts!");
+        }
+
+        ("modify", Some(m)) => {
+            let mut repo = Repository::open(m.value_of("repo").unwrap_or("."))?;
+
+            git::modify(
+                &mut repo,
+                m.value_of("remote"),
+                m.value_of("message"),
+                m.is_present("amend"),
+            )?;
+            println!("All done!");
+        }
+
+        ("absorb", Some(m)) => {
+            let mut repo = Repository::open(m.value_of("repo").unwrap_or("."))?;
+
+            absorb::absorb(&mut repo, m.value_of("base"))?;
+            println!("All done!");
+        }
+
+        (direction @ "up", Some(m))
+        | (direction @ "down", Some(m))
+        | (direction @ "top", Some(m))
+        | (direction @ "bottom", Some(m)) => {
+            let repo = Repository::open(m.value_of("repo").unwrap_or("."))?;
+
+            let parents = match m.value_of("identifier") {
+                Some(identifier) => {
+                    let stack = build_pr_stack(identifier, &credentials(), get_excluded(m)).await?;
+                    nav::pull_request_parents(&stack)
+                }
+                None => git::local_parents(&repo, m.value_of("base"))?,
+            };
+
+            let direction = match direction {
+                "up" => Direction::Up,
+                "down" => Direction::Down,
+                "top" => Direction::Top,
+                _ => Direction::Bottom,
+            };
+
+            nav::navigate(&repo, &parents, direction)?;
+        }
+
         (_, _) => panic!("Invalid subcommand."),
     }
 
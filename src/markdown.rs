@@ -19,7 +19,7 @@ pub fn build_table(deps: &FlatDep, title: &str, prelude_path: Option<&str>) -> S
     if let Some(prelude_path) = prelude_path {
         let prelude = fs::read_to_string(prelude_path).unwrap();
         out.push_str(&prelude);
-        out.push_str("\n");
+        out.push('\n');
     }
 
     out.push_str("| PR | Title | Status |  Merges Into  |\n");
@@ -0,0 +1,127 @@
+//! Moving between the branches of a stack. A stack is described by the branch each branch is
+//! based on; the branch at the very bottom (the trunk) isn't part of the stack itself.
+
+use dialoguer::Select;
+use git2::build::CheckoutBuilder;
+use git2::Repository;
+use std::collections::HashMap;
+use std::error::Error;
+
+use crate::api::PullRequestStatus;
+use crate::graph::FlatDep;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    /// The branch stacked on the current one
+    Up,
+    /// The branch the current one is based on
+    Down,
+    /// The last branch in the stack
+    Top,
+    /// The first branch in the stack (the one based on the trunk)
+    Bottom,
+}
+
+/// The base of every open PR in the stack, keyed by the PR's head.
+pub fn pull_request_parents(stack: &FlatDep) -> HashMap<String, String> {
+    stack
+        .iter()
+        .filter(|(pr, _)| *pr.state() == PullRequestStatus::Open)
+        .map(|(pr, _)| (pr.head().to_string(), pr.base().to_string()))
+        .collect()
+}
+
+fn current_branch(repo: &Repository) -> Result<String, Box<dyn Error>> {
+    let head = repo.head()?;
+    if !head.is_branch() {
+        return Err("HEAD is detached; check out a branch in the stack first.".into());
+    }
+
+    Ok(head.shorthand().unwrap().to_string())
+}
+
+/// The branch above `branch`; when several branches are stacked on it, the user picks one.
+fn child(
+    parents: &HashMap<String, String>,
+    branch: &str,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let mut children = parents
+        .iter()
+        .filter(|(_, parent)| *parent == branch)
+        .map(|(child, _)| child.clone())
+        .collect::<Vec<_>>();
+    children.sort();
+
+    if children.len() > 1 {
+        let picked = Select::new()
+            .with_prompt(format!("{} has more than one branch on top of it", branch))
+            .items(&children)
+            .default(0)
+            .interact()?;
+        return Ok(Some(children.swap_remove(picked)));
+    }
+
+    Ok(children.pop())
+}
+
+/// Check out the branch in `direction` from the current branch, given the parent of every
+/// branch in the stack.
+pub fn navigate(
+    repo: &Repository,
+    parents: &HashMap<String, String>,
+    direction: Direction,
+) -> Result<(), Box<dyn Error>> {
+    let current = current_branch(repo)?;
+
+    let target = match direction {
+        Direction::Up => child(parents, &current)?
+            .ok_or_else(|| format!("{} is at the top of the stack", current))?,
+        Direction::Down => match parents.get(&current) {
+            Some(parent) if parents.contains_key(parent) => parent.clone(),
+            Some(_) => return Err(format!("{} is at the bottom of the stack", current).into()),
+            None => return Err(format!("{} isn't part of a stack", current).into()),
+        },
+        Direction::Top => {
+            let mut branch = current.clone();
+            while let Some(next) = child(parents, &branch)? {
+                branch = next;
+            }
+            branch
+        }
+        Direction::Bottom => {
+            let mut branch = current.clone();
+            if !parents.contains_key(&branch) {
+                return Err(format!("{} isn't part of a stack", current).into());
+            }
+            while let Some(parent) = parents.get(&branch).filter(|p| parents.contains_key(*p)) {
+                branch = parent.clone();
+            }
+            branch
+        }
+    };
+
+    if target == current {
+        println!("Already on {}", current);
+        return Ok(());
+    }
+
+    let refname = format!("refs/heads/{}", target);
+    let commit = repo
+        .find_reference(&refname)
+        .map_err(|_| {
+            format!(
+                "There's no local branch for {}; try `gh-stack checkout`",
+                target
+            )
+        })?
+        .peel_to_commit()?;
+
+    // Like `git checkout`, refuse to overwrite uncommitted changes
+    let mut cb = CheckoutBuilder::new();
+    cb.safe();
+    repo.checkout_tree(commit.as_object(), Some(&mut cb))?;
+    repo.set_head(&refname)?;
+
+    println!("Checked out {}", target);
+    Ok(())
+}
@@ -0,0 +1,206 @@
+use git2::{
+    Cred, CredentialType, Direction, Error, FetchOptions, Oid, PushOptions, RemoteCallbacks,
+    Repository,
+};
+use std::collections::HashMap;
+
+const PUSH_REF_PREFIX: &str = "refs/gh-stack/push";
+const LEASE_REF_PREFIX: &str = "refs/gh-stack/lease";
+
+/// Callbacks that authenticate against the remote the same way `git` would: via the SSH
+/// agent for SSH remotes, and the credential helper configured for `repo` (including in its
+/// own `.git/config`) for HTTPS remotes.
+pub fn callbacks<'a>(repo: &Repository) -> Result<RemoteCallbacks<'a>, Error> {
+    let config = repo.config()?;
+    let mut attempts = 0;
+
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(move |url, username, allowed| {
+        // libgit2 keeps asking for as long as authentication fails
+        attempts += 1;
+        if attempts > 3 {
+            return Err(Error::from_str("Authentication failed"));
+        }
+
+        if allowed.contains(CredentialType::SSH_KEY) {
+            Cred::ssh_key_from_agent(username.unwrap_or("git"))
+        } else if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            Cred::credential_helper(&config, url, username)
+        } else {
+            Cred::default()
+        }
+    });
+
+    Ok(callbacks)
+}
+
+/// Update the remote-tracking branches for `branches` from `remote`. Returns what each of them
+/// pointed at before the fetch (a zero SHA if it didn't exist), which is what pushes have to be
+/// leased on: the local branches were built on top of those, not on whatever was just fetched.
+pub fn fetch(
+    repo: &Repository,
+    remote: &str,
+    branches: &[&str],
+) -> Result<HashMap<String, Oid>, Error> {
+    let previous = branches
+        .iter()
+        .map(|branch| {
+            let tracking = repo
+                .refname_to_id(&format!("refs/remotes/{}/{}", remote, branch))
+                .unwrap_or_else(|_| Oid::zero());
+            (branch.to_string(), tracking)
+        })
+        .collect();
+
+    let refspecs = branches
+        .iter()
+        .map(|branch| format!("+refs/heads/{0}:refs/remotes/{1}/{0}", branch, remote))
+        .collect::<Vec<_>>();
+
+    println!("Fetching {} from {}", branches.join(", "), remote);
+    fetch_refspecs(repo, remote, &refspecs)?;
+
+    Ok(previous)
+}
+
+pub fn fetch_refspecs(repo: &Repository, remote: &str, refspecs: &[String]) -> Result<(), Error> {
+    let mut options = FetchOptions::new();
+    options.remote_callbacks(callbacks(repo)?);
+
+    repo.find_remote(remote)?
+        .fetch(refspecs, Some(&mut options), None)
+}
+
+fn lease_ref(remote: &str, branch: &str) -> String {
+    format!("{}/{}/{}", LEASE_REF_PREFIX, remote, branch)
+}
+
+/// The lease recorded for `branch` on `remote` by `record_lease`, if it hasn't been pushed yet.
+pub fn recorded_lease(repo: &Repository, remote: &str, branch: &str) -> Option<Oid> {
+    repo.refname_to_id(&lease_ref(remote, branch)).ok()
+}
+
+/// Remember that `branch` was rewritten on top of `lease` without being pushed, so a later push
+/// is still leased on `lease` (rather than on whatever has been fetched since). An earlier lease
+/// that hasn't been pushed yet is kept, since the local branch was built on that one. Zero
+/// leases (the branch doesn't exist on `remote`) can't be stored in a ref, and don't need to be.
+pub fn record_lease(
+    repo: &Repository,
+    remote: &str,
+    branch: &str,
+    lease: Oid,
+) -> Result<(), Error> {
+    let name = lease_ref(remote, branch);
+
+    if lease.is_zero() || repo.find_reference(&name).is_ok() {
+        return Ok(());
+    }
+
+    repo.reference(&name, lease, false, "gh-stack: lease")?;
+    Ok(())
+}
+
+/// The SHAs of every branch on `remote`, as the remote sees them right now.
+fn remote_branches(repo: &Repository, remote: &str) -> Result<HashMap<String, Oid>, Error> {
+    let mut remote = repo.find_remote(remote)?;
+    let connection = remote.connect_auth(Direction::Push, Some(callbacks(repo)?), None)?;
+
+    let branches = connection
+        .list()?
+        .iter()
+        .filter(|head| head.name().starts_with("refs/heads/"))
+        .map(|head| (head.name().to_string(), head.oid()))
+        .collect();
+
+    Ok(branches)
+}
+
+/// Force-push each `(branch, new SHA, expected remote SHA)` in `leases`, as long as the remote
+/// branch still points at the expected SHA (a zero SHA means the branch mustn't exist yet).
+/// Returns the branches that weren't updated. Branches that were are done with their recorded
+/// leases, if they had any.
+///
+/// `libgit2` doesn't support refspecs containing raw SHAs
+/// (https://github.com/libgit2/libgit2/issues/1125), so every new SHA gets a temporary local
+/// ref that is pushed instead. There's no `--force-with-lease` either, so leases are checked
+/// against the remote's advertised refs just before pushing.
+pub fn push_with_lease(
+    repo: &Repository,
+    remote: &str,
+    leases: &[(&str, Oid, Oid)],
+) -> Result<Vec<String>, Error> {
+    let current = remote_branches(repo, remote)?;
+    let mut rejected = vec![];
+    let mut refspecs = vec![];
+    let mut temporary_refs = vec![];
+
+    for (branch, target, lease) in leases {
+        let remote_ref = format!("refs/heads/{}", branch);
+
+        if *current.get(&remote_ref).unwrap_or(&Oid::zero()) != *lease {
+            println!("  ! Branch {} was rejected (stale info)", branch);
+            rejected.push(branch.to_string());
+            continue;
+        }
+
+        let local_ref = format!("{}/{}", PUSH_REF_PREFIX, branch);
+        temporary_refs.push(repo.reference(&local_ref, *target, true, "gh-stack: push")?);
+        refspecs.push(format!("+{}:{}", local_ref, remote_ref));
+    }
+
+    let mut statuses = vec![];
+
+    if !refspecs.is_empty() {
+        let mut callbacks = callbacks(repo)?;
+        callbacks.push_update_reference(|refname, status| {
+            statuses.push((refname.to_string(), status.map(String::from)));
+            Ok(())
+        });
+
+        let mut options = PushOptions::new();
+        options.remote_callbacks(callbacks);
+
+        let result = repo
+            .find_remote(remote)?
+            .push(refspecs.as_slice(), Some(&mut options));
+
+        for mut reference in temporary_refs {
+            reference.delete()?;
+        }
+
+        result?;
+    }
+
+    for (refname, status) in statuses {
+        let branch = refname.trim_start_matches("refs/heads/");
+
+        match status {
+            None => {
+                println!("  + Branch {} was pushed", branch);
+
+                if let Ok(mut lease) = repo.find_reference(&lease_ref(remote, branch)) {
+                    lease.delete()?;
+                }
+            }
+            Some(message) => {
+                println!("  ! Branch {} was rejected ({})", branch, message);
+                rejected.push(branch.to_string());
+            }
+        }
+    }
+
+    Ok(rejected)
+}
+
+/// Turn the branches `push_with_lease` couldn't update into an error.
+pub fn check_rejected(rejected: Vec<String>) -> Result<(), Box<dyn std::error::Error>> {
+    if rejected.is_empty() {
+        return Ok(());
+    }
+
+    Err(format!(
+        "The remote rejected updates to these branches: {}",
+        rejected.join(", ")
+    )
+    .into())
+}
@@ -0,0 +1,358 @@
+//! Record how conflicts were resolved, and replay those resolutions when the same conflict
+//! comes up again (on the next restack, say), like `git rerere`. A conflict is identified by
+//! its conflict hunks (what each side has between the conflict markers), not by the whole
+//! file, so a resolution still applies after the rest of the file has changed (once the trunk
+//! has moved on, say). The conflicted file (the "preimage") and its resolution (the
+//! "postimage") are kept as refs to blobs, so they aren't garbage-collected.
+
+use git2::build::{CheckoutBuilder, TreeUpdateBuilder};
+use git2::{FileMode, Index, IndexEntry, ObjectType, Oid, Repository};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const RERERE_REF_PREFIX: &str = "refs/gh-stack/rerere";
+
+/// The bits of `IndexEntry::flags` that hold the entry's stage.
+pub const STAGE_MASK: u16 = 0x3000;
+
+/// A conflicted path in an index.
+pub struct Conflict {
+    pub path: PathBuf,
+    /// The normalized preimage and the key of its conflict hunks (see `normalize`); only
+    /// textual conflicts where all three versions exist can be recorded
+    preimage: Option<(Oid, Vec<u8>)>,
+    ours: Option<IndexEntry>,
+}
+
+/// The paths in `index` that have conflicts.
+pub fn conflicted_paths(index: &Index) -> Result<Vec<PathBuf>, git2::Error> {
+    let mut out = vec![];
+
+    for conflict in index.conflicts()? {
+        let conflict = conflict?;
+        if let Some(entry) = conflict.our.as_ref().or(conflict.their.as_ref()) {
+            out.push(PathBuf::from(
+                String::from_utf8_lossy(&entry.path).into_owned(),
+            ));
+        }
+    }
+
+    Ok(out)
+}
+
+pub fn conflicts(repo: &Repository, index: &mut Index) -> Result<Vec<Conflict>, Box<dyn Error>> {
+    let mut out = vec![];
+    let mut textual = vec![];
+
+    for conflict in index.conflicts()? {
+        let conflict = conflict?;
+        let path = match conflict.our.as_ref().or(conflict.their.as_ref()) {
+            Some(entry) => PathBuf::from(String::from_utf8_lossy(&entry.path).into_owned()),
+            None => continue,
+        };
+
+        textual.push(
+            conflict.ancestor.is_some() && conflict.our.is_some() && conflict.their.is_some(),
+        );
+        out.push(Conflict {
+            path,
+            preimage: None,
+            ours: conflict.our,
+        });
+    }
+
+    // Done once the conflicts have been read, since checking the files out needs the index
+    for (conflict, textual) in out.iter_mut().zip(textual) {
+        if textual {
+            conflict.preimage = normalize(&with_markers(repo, index, &conflict.path)?);
+        }
+    }
+
+    Ok(out)
+}
+
+/// The conflicted file at `path`, with conflict markers, the way it's checked out in the
+/// working tree. It's checked out into a scratch directory, since `index` might only exist in
+/// memory.
+fn with_markers(
+    repo: &Repository,
+    index: &mut Index,
+    path: &Path,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let scratch = repo.path().join("gh-stack-rerere");
+
+    let mut cb = CheckoutBuilder::new();
+    cb.force()
+        .update_index(false)
+        .allow_conflicts(true)
+        .conflict_style_merge(true)
+        .target_dir(&scratch)
+        .path(path);
+    let checkout = repo.checkout_index(Some(index), Some(&mut cb));
+
+    let content = checkout.map_err(Box::from).and_then(|()| {
+        fs::read(scratch.join(path)).map_err(|e| format!("{}: {}", path.display(), e).into())
+    });
+    let _ = fs::remove_dir_all(&scratch);
+    content
+}
+
+/// Normalize a file with conflict markers the way `git rerere` does: marker labels and the
+/// common ancestor's section (in diff3 style) are dropped, and the two sides of each conflict
+/// hunk are put in a fixed order, so the same conflict is recognized whichever side it's seen
+/// from. Returns the key of the conflict hunks (the content outside them doesn't affect it)
+/// and the normalized file, or `None` if there are no conflict markers.
+fn normalize(text: &[u8]) -> Option<(Oid, Vec<u8>)> {
+    enum Section {
+        Outside,
+        Ours,
+        Base,
+        Theirs,
+    }
+
+    let is_marker = |line: &[u8], marker: u8| {
+        line.len() >= 7
+            && line[..7].iter().all(|byte| *byte == marker)
+            && matches!(line.get(7), None | Some(b' ') | Some(b'\n') | Some(b'\r'))
+    };
+
+    let mut section = Section::Outside;
+    let mut normalized = vec![];
+    let mut hunks = vec![];
+    let (mut ours, mut theirs) = (vec![], vec![]);
+
+    for line in text.split_inclusive(|byte| *byte == b'\n') {
+        section = match section {
+            Section::Outside if is_marker(line, b'<') => Section::Ours,
+            Section::Outside => {
+                normalized.extend_from_slice(line);
+                Section::Outside
+            }
+            Section::Ours | Section::Base if is_marker(line, b'=') => Section::Theirs,
+            Section::Ours if is_marker(line, b'|') => Section::Base,
+            Section::Ours => {
+                ours.extend_from_slice(line);
+                Section::Ours
+            }
+            Section::Base => Section::Base,
+            Section::Theirs if is_marker(line, b'>') => {
+                let (one, two) = if ours <= theirs {
+                    (&ours, &theirs)
+                } else {
+                    (&theirs, &ours)
+                };
+
+                for (bytes, marker) in [(one, &b"<<<<<<<\n"[..]), (two, b"=======\n")] {
+                    normalized.extend_from_slice(marker);
+                    normalized.extend_from_slice(bytes);
+                    hunks.extend_from_slice(bytes);
+                    hunks.push(0);
+                }
+                normalized.extend_from_slice(b">>>>>>>\n");
+
+                ours.clear();
+                theirs.clear();
+                Section::Outside
+            }
+            Section::Theirs => {
+                theirs.extend_from_slice(line);
+                Section::Theirs
+            }
+        };
+    }
+
+    if hunks.is_empty() {
+        return None;
+    }
+
+    let key = Oid::hash_object(ObjectType::Blob, &hunks).ok()?;
+    Some((key, normalized))
+}
+
+fn recorded_image(repo: &Repository, key: Oid, image: &str) -> Option<Vec<u8>> {
+    let oid = repo
+        .refname_to_id(&format!("{}/{}/{}", RERERE_REF_PREFIX, key, image))
+        .ok()?;
+    Some(repo.find_blob(oid).ok()?.content().to_vec())
+}
+
+/// Three-way merge `ours` and `theirs` (both changed from `base`), if that's possible without
+/// conflicts. Done by merging one-file trees, since there's no way to merge buffers directly.
+fn merge_contents(
+    repo: &Repository,
+    base: &[u8],
+    ours: &[u8],
+    theirs: &[u8],
+) -> Result<Option<Vec<u8>>, git2::Error> {
+    let empty = repo.find_tree(repo.treebuilder(None)?.write()?)?;
+    let tree = |content: &[u8]| -> Result<git2::Tree, git2::Error> {
+        let mut update = TreeUpdateBuilder::new();
+        update.upsert("file", repo.blob(content)?, FileMode::Blob);
+        repo.find_tree(update.create_updated(repo, &empty)?)
+    };
+
+    let index = repo.merge_trees(&tree(base)?, &tree(ours)?, &tree(theirs)?, None)?;
+    if index.has_conflicts() {
+        return Ok(None);
+    }
+
+    match index.get_path(Path::new("file"), 0) {
+        Some(entry) => Ok(Some(repo.find_blob(entry.id)?.content().to_vec())),
+        None => Ok(None),
+    }
+}
+
+/// The recorded resolution of `conflict`, adapted to the rest of the file: the change from the
+/// recorded preimage to its postimage is merged into the current preimage, like `git rerere`
+/// does.
+fn resolution(repo: &Repository, conflict: &Conflict) -> Result<Option<Vec<u8>>, git2::Error> {
+    let (key, current) = match &conflict.preimage {
+        Some(preimage) => preimage,
+        None => return Ok(None),
+    };
+
+    let (preimage, postimage) = match (
+        recorded_image(repo, *key, "preimage"),
+        recorded_image(repo, *key, "postimage"),
+    ) {
+        (Some(preimage), Some(postimage)) => (preimage, postimage),
+        _ => return Ok(None),
+    };
+
+    if preimage == *current {
+        return Ok(Some(postimage));
+    }
+
+    merge_contents(repo, &preimage, current, &postimage)
+}
+
+/// Resolve every conflict in the in-memory `index` that has a recorded resolution. Returns
+/// whether any conflicts are left.
+pub fn resolve_in_index(repo: &Repository, index: &mut Index) -> Result<bool, Box<dyn Error>> {
+    for conflict in conflicts(repo, index)? {
+        let resolved = match resolution(repo, &conflict)? {
+            Some(resolved) => resolved,
+            None => continue,
+        };
+
+        if let Some(mut entry) = conflict.ours {
+            entry.id = repo.blob(&resolved)?;
+            entry.file_size = resolved.len() as u32;
+            entry.flags &= !STAGE_MASK;
+
+            for stage in 1..=3 {
+                index.remove(&conflict.path, stage)?;
+            }
+            index.add(&entry)?;
+            println!(
+                "Reused the recorded resolution for {}",
+                conflict.path.display()
+            );
+        }
+    }
+
+    Ok(index.has_conflicts())
+}
+
+/// Resolve every conflict in the working tree (and the repository's index) that has a recorded
+/// resolution, so only new conflicts are left for the user.
+pub fn resolve_in_workdir(
+    repo: &Repository,
+    index: &mut Index,
+    conflicts: &[Conflict],
+) -> Result<(), Box<dyn Error>> {
+    let workdir = repo
+        .workdir()
+        .ok_or("The repository doesn't have a working tree")?;
+
+    for conflict in conflicts {
+        if let Some(resolved) = resolution(repo, conflict)? {
+            fs::write(workdir.join(&conflict.path), resolved)?;
+            index.add_path(&conflict.path)?;
+            println!(
+                "Reused the recorded resolution for {}",
+                conflict.path.display()
+            );
+        }
+    }
+
+    index.write()?;
+    Ok(())
+}
+
+/// Record how each of `conflicts` was resolved in `index`.
+pub fn record(
+    repo: &Repository,
+    conflicts: &[Conflict],
+    index: &Index,
+) -> Result<(), Box<dyn Error>> {
+    for conflict in conflicts {
+        let (key, preimage) = match &conflict.preimage {
+            Some(preimage) => preimage,
+            None => continue,
+        };
+
+        if let Some(entry) = index.get_path(&conflict.path, 0) {
+            let prefix = format!("{}/{}", RERERE_REF_PREFIX, key);
+            let preimage = repo.blob(preimage)?;
+            repo.reference(
+                &format!("{}/preimage", prefix),
+                preimage,
+                true,
+                "gh-stack: record conflict",
+            )?;
+            repo.reference(
+                &format!("{}/postimage", prefix),
+                entry.id,
+                true,
+                "gh-stack: record resolution",
+            )?;
+            println!("Recorded the resolution for {}", conflict.path.display());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_ignores_files_without_conflicts() {
+        assert!(normalize(b"a\nb\n").is_none());
+    }
+
+    #[test]
+    fn normalize_drops_labels_and_sorts_sides() {
+        let (key, normalized) =
+            normalize(b"a\n<<<<<<< ours\nz\n=======\ny\n>>>>>>> theirs\nb\n").unwrap();
+        assert_eq!(normalized, b"a\n<<<<<<<\ny\n=======\nz\n>>>>>>>\nb\n");
+
+        let (swapped, _) =
+            normalize(b"a\n<<<<<<< HEAD\ny\n=======\nz\n>>>>>>> 1234567 Commit\nb\n").unwrap();
+        assert_eq!(key, swapped);
+    }
+
+    #[test]
+    fn normalize_drops_the_common_ancestor() {
+        let (key, normalized) =
+            normalize(b"<<<<<<< ours\ny\n||||||| base\nx\n=======\nz\n>>>>>>> theirs\n").unwrap();
+        assert_eq!(normalized, b"<<<<<<<\ny\n=======\nz\n>>>>>>>\n");
+        assert_eq!(
+            key,
+            normalize(b"<<<<<<<\ny\n=======\nz\n>>>>>>>\n").unwrap().0
+        );
+    }
+
+    #[test]
+    fn normalize_keys_on_the_conflict_hunks_only() {
+        let (key, _) = normalize(b"a\n<<<<<<<\ny\n=======\nz\n>>>>>>>\nb\n").unwrap();
+        let (moved, _) = normalize(b"new\na\nb\n<<<<<<<\ny\n=======\nz\n>>>>>>>\n").unwrap();
+        let (different, _) = normalize(b"a\n<<<<<<<\ny\n=======\nw\n>>>>>>>\nb\n").unwrap();
+
+        assert_eq!(key, moved);
+        assert_ne!(key, different);
+    }
+}